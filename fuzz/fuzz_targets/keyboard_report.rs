@@ -0,0 +1,13 @@
+#![no_main]
+#![allow(dead_code)]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/report.rs"]
+mod report;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(report) = report::KeyboardReport::parse(data) {
+        assert_eq!(report.keys, data[2..]);
+    }
+});
@@ -0,0 +1,18 @@
+#![no_main]
+#![allow(dead_code)]
+
+use libfuzzer_sys::fuzz_target;
+use pnet_packet::usbpcap::UsbPcapPacket;
+
+#[path = "../../src/device.rs"]
+mod device;
+#[path = "../../src/report.rs"]
+mod report;
+
+// the decoding done on each packet read from the driver
+fuzz_target!(|data: &[u8]| {
+    if let Some(packet) = UsbPcapPacket::new(data) {
+        let _ = report::keyboard_report(&packet);
+        let _ = device::DeviceTable::default().learn(&packet);
+    }
+});
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+struct State {
+    /// Consecutive failures.
+    failures: u32,
+    disabled_until: Option<Instant>,
+}
+
+/// Disables the actions that keep failing for a while, so that a broken mapping doesn't spam
+/// errors on every key press. Driven by an explicit `now`, like the scheduler's timers.
+pub struct CircuitBreaker<K> {
+    threshold: u32,
+    cooldown: Duration,
+    states: HashMap<K, State>,
+}
+
+impl<K: Hash + Eq> CircuitBreaker<K> {
+    /// Disables an action for `cooldown` after `threshold` consecutive failures.
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            states: HashMap::new(),
+        }
+    }
+
+    /// Whether the action can run. Once the cooldown is over, it gets a single try before
+    /// being disabled again.
+    pub fn allows(&mut self, key: &K, now: Instant) -> bool {
+        let state = match self.states.get_mut(key) {
+            Some(state) => state,
+            None => return true,
+        };
+        match state.disabled_until {
+            Some(until) if now < until => false,
+            Some(_) => {
+                state.disabled_until = None;
+                state.failures = self.threshold.saturating_sub(1);
+                true
+            }
+            None => true,
+        }
+    }
+
+    pub fn success(&mut self, key: &K) {
+        self.states.remove(key);
+    }
+
+    /// Records a failure, returning whether it disables the action.
+    pub fn failure(&mut self, key: K, now: Instant) -> bool {
+        let state = self.states.entry(key).or_insert(State {
+            failures: 0,
+            disabled_until: None,
+        });
+        state.failures += 1;
+        if state.failures >= self.threshold {
+            state.disabled_until = Some(now + self.cooldown);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::CircuitBreaker;
+
+    const COOLDOWN: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn disables_after_the_threshold() {
+        let now = Instant::now();
+        let mut breaker = CircuitBreaker::new(3, COOLDOWN);
+        assert!(!breaker.failure(1, now));
+        assert!(!breaker.failure(1, now));
+        assert!(breaker.allows(&1, now));
+        assert!(breaker.failure(1, now));
+        assert!(!breaker.allows(&1, now));
+        assert!(!breaker.allows(&1, now + COOLDOWN - Duration::from_millis(1)));
+        // the other actions aren't affected
+        assert!(breaker.allows(&2, now));
+    }
+
+    #[test]
+    fn gives_one_more_try_after_the_cooldown() {
+        let now = Instant::now();
+        let mut breaker = CircuitBreaker::new(3, COOLDOWN);
+        for _ in 0..3 {
+            breaker.failure(1, now);
+        }
+        let later = now + COOLDOWN;
+        assert!(breaker.allows(&1, later));
+        // a single failure disables it again
+        assert!(breaker.failure(1, later));
+        assert!(!breaker.allows(&1, later));
+        assert!(!breaker.allows(&1, later + COOLDOWN / 2));
+        assert!(breaker.allows(&1, later + COOLDOWN));
+    }
+
+    #[test]
+    fn success_resets_the_failures() {
+        let now = Instant::now();
+        let mut breaker = CircuitBreaker::new(3, COOLDOWN);
+        breaker.failure(1, now);
+        breaker.failure(1, now);
+        breaker.success(&1);
+        assert!(!breaker.failure(1, now));
+        assert!(!breaker.failure(1, now));
+        assert!(breaker.allows(&1, now));
+        // also after the cooldown
+        assert!(breaker.failure(1, now));
+        let later = now + COOLDOWN;
+        assert!(breaker.allows(&1, later));
+        breaker.success(&1);
+        assert!(!breaker.failure(1, later));
+        assert!(breaker.allows(&1, later));
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context};
+use pcap_parser::traits::PcapReaderIterator;
+use pcap_parser::{LegacyPcapReader, PcapBlockOwned, PcapError};
+use pnet_packet::usbpcap::UsbPcapPacket;
+
+use crate::config::{CaptureFilter, Config};
+use crate::device::{DeviceAddress, DeviceIds, DeviceTable, Interface};
+use crate::driver::UsbPcapDriver;
+use crate::ioctl::StartFiltering;
+use crate::reload;
+use crate::report;
+use crate::shutdown::{self, StopSignal};
+use crate::topology;
+use crate::usbhub::Win32Backend;
+use crate::{Error, DEFAULT_INTERNAL_KERNEL_BUFFER_SIZE};
+
+const DLT_USBPCAP: i32 = 249;
+
+/// The first delay before reopening a driver after an error.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// A capture running for this long is considered stable again, resetting the backoff.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+/// How often waiting for reports checks whether usbrawmap is asked to stop.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// How often stopping the capture cancels the pending reads again, until the threads exit.
+const CANCEL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// An event of the capture of one of the hubs.
+pub enum CaptureEvent {
+    Report(Interface, Option<DeviceIds>, Vec<u8>),
+    /// The capture failed and is being reopened, the keys held on the hub won't be released.
+    Disconnected(u8, anyhow::Error),
+    /// The driver couldn't be opened when starting, it is retried like after a disconnection.
+    Failed(u8, anyhow::Error),
+    /// The config was changed and loaded again, see `Capture::watch_config`.
+    Reload(Config),
+}
+
+/// What the capture threads send.
+enum Message {
+    Event(CaptureEvent),
+    Opened(u8),
+}
+
+/// Exponential delays between attempts to reopen a driver.
+pub struct Backoff {
+    next: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            next: INITIAL_BACKOFF,
+        }
+    }
+}
+
+impl Backoff {
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(MAX_BACKOFF);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = INITIAL_BACKOFF;
+    }
+}
+
+fn open_driver(driver: u8, filter: &CaptureFilter) -> anyhow::Result<UsbPcapDriver> {
+    let start_filtering = match filter {
+        CaptureFilter::All | CaptureFilter::Detect => StartFiltering::all(),
+        CaptureFilter::Addresses(addresses) => StartFiltering::addresses(addresses.clone()),
+    };
+    UsbPcapDriver::new(
+        Path::new(&format!(r"\\.\USBPcap{}", driver)),
+        start_filtering,
+    )
+    .with_context(|| format!("Failed to open USBPcap{}", driver))
+}
+
+/// Calls `on_keys` with the keys held in each keyboard report captured on the hub, along with
+/// the interface that sent it and the IDs of its device if they are known, until `stop`.
+fn read_reports<F>(
+    driver: &UsbPcapDriver,
+    filter: &CaptureFilter,
+    devices: &mut DeviceTable,
+    stop: &StopSignal,
+    mut on_keys: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(Interface, Option<DeviceIds>, &[u8]) -> anyhow::Result<()>,
+{
+    let mut detect = *filter == CaptureFilter::Detect;
+    let mut reader = LegacyPcapReader::new(DEFAULT_INTERNAL_KERNEL_BUFFER_SIZE, driver)
+        .context("Failed to create reader")?;
+    loop {
+        match reader.next() {
+            Ok((offset, block)) => {
+                match block {
+                    PcapBlockOwned::LegacyHeader(hdr) => {
+                        if hdr.network.0 == DLT_USBPCAP {
+                            println!("USBPcap header found");
+                        } else {
+                            bail!(Error::Message("Not USBPcap"));
+                        }
+                    }
+                    PcapBlockOwned::Legacy(b) => {
+                        let packet = UsbPcapPacket::new(b.data)
+                            .context("Failed to decode USBPcap packet")?;
+                        if let Some((address, ids)) = devices.learn(&packet) {
+                            println!("Device {} is {}", address, ids);
+                        }
+                        // other transfers, and reports that aren't boot keyboard reports or
+                        // are cut short, are skipped
+                        if let Ok(Some(report)) = report::keyboard_report(&packet) {
+                            let interface = Interface::of(&packet);
+                            if detect {
+                                if let Ok(address) = u8::try_from(interface.device.address) {
+                                    driver
+                                        .set_filter(StartFiltering::addresses([address]))
+                                        .context("Failed to change the capture filter")?;
+                                    println!("Capturing only {} from now on", interface.device);
+                                }
+                                detect = false;
+                            }
+                            let ids = devices.get(interface.device);
+                            on_keys(interface, ids, &report.keys)?;
+                        }
+                    }
+                    PcapBlockOwned::NG(_) => unreachable!(),
+                }
+                reader.consume(offset);
+            }
+            Err(PcapError::Eof | PcapError::Incomplete) => {
+                // cancelling only fails the pending read, a new one would block until the next
+                // packet
+                if stop.is_stopped() {
+                    return Ok(());
+                }
+                reader
+                    .refill()
+                    .map_err(|e| Error::Pcap(e.to_owned_vec()))
+                    .context("Failed to read from the driver")?;
+            }
+            Err(e) => {
+                bail!(Error::Pcap(e.to_owned_vec()));
+            }
+        }
+    }
+}
+
+/// Reads the IDs of the devices plugged into the hub of the driver, which won't send their
+/// descriptor until they are plugged again.
+fn read_device_ids(driver: u8, devices: &mut DeviceTable) {
+    let hub = topology::root_hub(&Win32Backend, driver);
+    if let Err(err) = &hub.devices {
+        println!("Failed to list the devices of USBPcap{}: {}", driver, err);
+    }
+    for connection in hub.connections() {
+        // USBPcap gives the number of the control device as the bus
+        let address = DeviceAddress {
+            bus: driver.into(),
+            address: connection.address,
+        };
+        devices.insert(address, connection.ids);
+    }
+}
+
+/// The drivers being read, so that their pending reads can be cancelled from another thread.
+#[derive(Clone, Default)]
+struct Readers(Arc<Mutex<Vec<Arc<UsbPcapDriver>>>>);
+
+impl Readers {
+    fn lock(&self) -> MutexGuard<'_, Vec<Arc<UsbPcapDriver>>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Registers the driver, unless the capture is stopped, in which case it mustn't be read.
+    fn add(&self, driver: &Arc<UsbPcapDriver>, stop: &StopSignal) -> bool {
+        let mut readers = self.lock();
+        if stop.is_stopped() {
+            return false;
+        }
+        readers.push(driver.clone());
+        true
+    }
+
+    fn remove(&self, driver: &Arc<UsbPcapDriver>) {
+        self.lock().retain(|other| !Arc::ptr_eq(other, driver));
+    }
+
+    /// Stops the capture, making the pending reads fail.
+    fn stop(&self, stop: &StopSignal) {
+        let readers = self.lock();
+        stop.stop();
+        for driver in readers.iter() {
+            driver.cancel();
+        }
+    }
+
+    /// Makes the pending reads fail again.
+    fn cancel(&self) {
+        for driver in self.lock().iter() {
+            driver.cancel();
+        }
+    }
+}
+
+/// Captures a hub until it is stopped or the receiver is dropped, reopening the driver with a
+/// backoff when the capture fails, e.g. when the computer resumes from sleep.
+fn supervise<F>(
+    driver: u8,
+    filter: &CaptureFilter,
+    readers: &Readers,
+    stop: &StopSignal,
+    mut send: F,
+) where
+    F: FnMut(Message) -> bool,
+{
+    let mut opened = match open_driver(driver, filter) {
+        Ok(opened) => Some(opened),
+        // e.g. held exclusively by Wireshark, the other hubs are still captured meanwhile
+        Err(err) => {
+            if !send(Message::Event(CaptureEvent::Failed(driver, err))) {
+                return;
+            }
+            None
+        }
+    };
+    let mut was_opened = opened.is_some();
+    // kept across reopens, the devices only send their descriptor when plugged in
+    let mut devices = DeviceTable::default();
+    let mut backoff = Backoff::default();
+    loop {
+        let usbpcap = match opened.take() {
+            Some(usbpcap) => usbpcap,
+            None => {
+                if stop.wait(backoff.next_delay()) {
+                    return;
+                }
+                match open_driver(driver, filter) {
+                    Ok(usbpcap) => {
+                        if was_opened {
+                            println!("Reconnected to USBPcap{}", driver);
+                        } else {
+                            println!("Opened USBPcap{}", driver);
+                            was_opened = true;
+                        }
+                        usbpcap
+                    }
+                    Err(err) => {
+                        println!("{:#}, retrying", err);
+                        continue;
+                    }
+                }
+            }
+        };
+        let usbpcap = Arc::new(usbpcap);
+        if !readers.add(&usbpcap, stop) || !send(Message::Opened(driver)) {
+            return;
+        }
+        // devices may have been plugged in while the hub wasn't captured
+        read_device_ids(driver, &mut devices);
+        let started = Instant::now();
+        let mut stopped = false;
+        let res = read_reports(
+            &usbpcap,
+            filter,
+            &mut devices,
+            stop,
+            |interface, ids, keys| {
+                stopped = !send(Message::Event(CaptureEvent::Report(
+                    interface,
+                    ids,
+                    keys.to_vec(),
+                )));
+                if stopped {
+                    Err(anyhow!("Capture stopped"))
+                } else {
+                    Ok(())
+                }
+            },
+        );
+        // the handle must be closed before the driver can be opened again
+        readers.remove(&usbpcap);
+        drop(usbpcap);
+        if stopped || stop.is_stopped() {
+            return;
+        }
+        let err = match res {
+            Ok(()) => anyhow!("Capture ended"),
+            Err(err) => err,
+        };
+        if started.elapsed() >= STABLE_AFTER {
+            backoff.reset();
+        }
+        if !send(Message::Event(CaptureEvent::Disconnected(driver, err))) {
+            return;
+        }
+    }
+}
+
+/// The capture of the hubs, each on its own thread, stopped when dropped.
+pub struct Capture {
+    events: Receiver<Message>,
+    /// For the threads that aren't reading a hub, such as the config watcher.
+    sender: Sender<Message>,
+    hubs: usize,
+    /// The hubs opened at least once, and the ones that failed to open when starting.
+    opened: HashSet<u8>,
+    failed: HashSet<u8>,
+    readers: Readers,
+    stop: StopSignal,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Capture {
+    /// Captures the reports of the given distinct hubs, each with its filter, sending them all
+    /// to a single channel.
+    pub fn start(hubs: &[(u8, CaptureFilter)]) -> Capture {
+        let (tx, rx) = mpsc::channel();
+        let readers = Readers::default();
+        let stop = StopSignal::default();
+        let threads = hubs
+            .iter()
+            .map(|(driver, filter)| {
+                let driver = *driver;
+                let tx = tx.clone();
+                let filter = filter.clone();
+                let readers = readers.clone();
+                let stop = stop.clone();
+                thread::spawn(move || {
+                    supervise(driver, &filter, &readers, &stop, |event| {
+                        tx.send(event).is_ok()
+                    })
+                })
+            })
+            .collect();
+        Capture {
+            events: rx,
+            sender: tx,
+            hubs: hubs.len(),
+            opened: HashSet::new(),
+            failed: HashSet::new(),
+            readers,
+            stop,
+            threads,
+        }
+    }
+
+    /// Delivers the config as a `Reload` event each time it is loaded again, so that it is
+    /// applied even while no key is pressed.
+    pub fn watch_config(&self, path: &Path, driver: Option<u8>) {
+        let sender = self.sender.clone();
+        reload::watch(path, driver, move |config| {
+            sender
+                .send(Message::Event(CaptureEvent::Reload(config)))
+                .is_ok()
+        });
+    }
+
+    /// The next event, or `None` once usbrawmap is asked to stop.
+    pub fn next_event(&mut self) -> Option<CaptureEvent> {
+        loop {
+            if shutdown::requested() {
+                return None;
+            }
+            match self.events.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+                Ok(Message::Opened(driver)) => {
+                    self.opened.insert(driver);
+                }
+                Ok(Message::Event(event)) => {
+                    if let CaptureEvent::Failed(driver, _) = event {
+                        self.failed.insert(driver);
+                    }
+                    return Some(event);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
+    /// Whether every hub failed to open, none being captured.
+    pub fn all_failed(&self) -> bool {
+        self.opened.is_empty() && self.failed.len() == self.hubs
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        self.readers.stop(&self.stop);
+        for thread in self.threads.drain(..) {
+            // a thread that checked the stop just before it was set starts its read after the
+            // cancellation, and only sees the stop once that read is cancelled too
+            while !thread.is_finished() {
+                thread::sleep(CANCEL_INTERVAL);
+                self.readers.cancel();
+            }
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,759 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use figment::value::{Dict, Value};
+
+use crate::config::{Capture, Config, Driver, DriverMode, Layer};
+use crate::hid::{self, UsageError};
+use crate::mappings::MappingAction;
+use crate::vk::{KeySequence, VK};
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+pub struct Problem {
+    pub severity: Severity,
+    /// Layer name, with the line and column if known.
+    pub location: String,
+    pub message: String,
+    /// The problem is with something outside the config, e.g. a program that isn't installed.
+    pub external: bool,
+}
+
+impl Problem {
+    /// Whether the config can't be used. An external problem only makes the mappings involved
+    /// fail, like when it appears while usbrawmap is running.
+    pub fn prevents_loading(&self) -> bool {
+        self.severity == Severity::Error && !self.external
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {}: {}", self.location, severity, self.message)
+    }
+}
+
+/// Parses a bare or quoted key at the start of `s`, returning it and the rest of `s`.
+fn parse_key(s: &str) -> Option<(String, &str)> {
+    for quote in ['"', '\''] {
+        if let Some(rest) = s.strip_prefix(quote) {
+            let end = rest.find(quote)?;
+            return Some((rest[..end].to_string(), &rest[end + 1..]));
+        }
+    }
+    let end = s
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+        .unwrap_or(s.len());
+    (end > 0).then(|| (s[..end].to_string(), &s[end..]))
+}
+
+/// Parses a dotted key such as `devices."my pad".mappings` at the start of `s`.
+fn parse_dotted_key(mut s: &str) -> Option<(Vec<String>, &str)> {
+    let mut keys = Vec::new();
+    loop {
+        let (key, rest) = parse_key(s.trim_start())?;
+        keys.push(key);
+        s = rest.trim_start();
+        match s.strip_prefix('.') {
+            Some(rest) => s = rest,
+            None => return Some((keys, s)),
+        }
+    }
+}
+
+/// The full key of the table or key/value pair on each line of a TOML file.
+fn toml_keys(lines: &[String]) -> Vec<Option<Vec<String>>> {
+    let mut table = Vec::new();
+    lines
+        .iter()
+        .map(|line| {
+            let line = line.trim_start();
+            if let Some(header) = line.strip_prefix('[') {
+                // arrays of tables too
+                let header = header.strip_prefix('[').unwrap_or(header);
+                let (keys, rest) = parse_dotted_key(header)?;
+                if !rest.starts_with(']') {
+                    return None;
+                }
+                table = keys.clone();
+                return Some(keys);
+            }
+            let (keys, rest) = parse_dotted_key(line)?;
+            rest.starts_with('=')
+                .then(|| [table.clone(), keys].concat())
+        })
+        .collect()
+}
+
+/// The full key on each line of a JSON or YAML file, nested according to the indentation.
+fn indented_keys(lines: &[String]) -> Vec<Option<Vec<String>>> {
+    let mut parents: Vec<(usize, String)> = Vec::new();
+    lines
+        .iter()
+        .map(|line| {
+            let trimmed = line.trim_start();
+            let indent = line.len() - trimmed.len();
+            let (key, rest) = parse_key(trimmed)?;
+            if !rest.trim_start().starts_with(':') {
+                return None;
+            }
+            while parents.last().is_some_and(|(other, _)| *other >= indent) {
+                parents.pop();
+            }
+            parents.push((indent, key));
+            Some(parents.iter().map(|(_, key)| key.clone()).collect())
+        })
+        .collect()
+}
+
+/// Finds the position of mappings in the text of a config file.
+struct Source {
+    lines: Vec<String>,
+    /// The full key defined on each line, e.g. `mappings.116.type`, if any.
+    keys: Vec<Option<Vec<String>>>,
+}
+
+impl Source {
+    fn new(path: &Path) -> Option<Source> {
+        let text = fs::read_to_string(path).ok()?;
+        let indented = matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("json" | "yaml" | "yml")
+        );
+        Some(Source::parse(&text, indented))
+    }
+
+    /// `indented` for JSON and YAML, TOML otherwise.
+    fn parse(text: &str, indented: bool) -> Source {
+        let lines: Vec<String> = text.lines().map(str::to_string).collect();
+        let keys = if indented {
+            indented_keys(&lines)
+        } else {
+            toml_keys(&lines)
+        };
+        Source { lines, keys }
+    }
+
+    /// The 1-based line and column of `token` in the mapping `key` of `table`, or of the
+    /// mapping itself.
+    fn locate(&self, table: &str, key: &str, token: Option<&str>) -> Option<(usize, usize)> {
+        let mapping: Vec<String> = table.split('.').chain([key]).map(str::to_string).collect();
+        let in_mapping = |i: usize| self.keys[i].as_ref().map(|keys| keys.starts_with(&mapping));
+        let start = (0..self.lines.len()).find(|&i| in_mapping(i) == Some(true))?;
+        if let Some(token) = token {
+            for (i, line) in self.lines.iter().enumerate().skip(start) {
+                // the mapping ends at the first line defining something else
+                if in_mapping(i) == Some(false) {
+                    break;
+                }
+                if let Some(col) = line.find(token) {
+                    return Some((i + 1, line[..col].chars().count() + 1));
+                }
+            }
+        }
+        let line = &self.lines[start];
+        Some((start + 1, line.len() - line.trim_start().len() + 1))
+    }
+}
+
+#[derive(Default)]
+struct Checker {
+    problems: Vec<Problem>,
+    /// Where each scancode is mapped in each table, to find duplicates.
+    mapped: HashMap<(String, u8), Vec<(String, String)>>,
+}
+
+impl Checker {
+    fn report(
+        &mut self,
+        severity: Severity,
+        layer: &Layer,
+        source: Option<&Source>,
+        position: Option<(usize, usize)>,
+        message: String,
+    ) -> &mut Problem {
+        let location = match (&layer.path, source.and(position)) {
+            (Some(path), Some((line, col))) => format!("{}:{}:{}", path.display(), line, col),
+            _ => layer.name.clone(),
+        };
+        self.problems.push(Problem {
+            severity,
+            location,
+            message,
+            external: false,
+        });
+        self.problems.last_mut().unwrap()
+    }
+
+    fn check_layer(&mut self, layer: &Layer) {
+        let source = layer.path.as_deref().and_then(Source::new);
+        let dict: Dict = match layer.figment.extract() {
+            Ok(dict) => dict,
+            Err(err) => {
+                self.report(Severity::Error, layer, None, None, err.to_string());
+                return;
+            }
+        };
+        if let Some(Value::Array(_, addresses)) = dict
+            .get("general")
+            .and_then(|general| general.find_ref("capture"))
+        {
+            for address in addresses {
+                if !matches!(address.to_u128(), Some(1..=127)) {
+                    self.report(
+                        Severity::Error,
+                        layer,
+                        None,
+                        None,
+                        "`general.capture` addresses go from 1 to 127".to_string(),
+                    );
+                    break;
+                }
+            }
+        }
+        if is_zero_rate(
+            dict.get("general")
+                .and_then(|general| general.find_ref("repeat")),
+        ) {
+            self.report(
+                Severity::Error,
+                layer,
+                None,
+                None,
+                "`general.repeat.rate_ms` must be at least 1".to_string(),
+            );
+        }
+        self.check_mappings(layer, source.as_ref(), "mappings", dict.get("mappings"));
+        match dict.get("devices") {
+            None => {}
+            Some(Value::Dict(_, devices)) => {
+                for (name, device) in devices {
+                    let table = format!("devices.{}", name);
+                    if ["vid", "pid", "bus", "address"]
+                        .iter()
+                        .all(|key| device.find_ref(key).is_none())
+                    {
+                        self.report(
+                            Severity::Warning,
+                            layer,
+                            None,
+                            None,
+                            format!(
+                                "`{}` has no vid, pid, bus or address, it never matches",
+                                table
+                            ),
+                        );
+                    }
+                    let table = format!("{}.mappings", table);
+                    self.check_mappings(
+                        layer,
+                        source.as_ref(),
+                        &table,
+                        device.find_ref("mappings"),
+                    );
+                }
+            }
+            Some(_) => {
+                self.report(
+                    Severity::Error,
+                    layer,
+                    None,
+                    None,
+                    "`devices` must be a table".to_string(),
+                );
+            }
+        }
+    }
+
+    fn check_mappings(
+        &mut self,
+        layer: &Layer,
+        source: Option<&Source>,
+        table: &str,
+        mappings: Option<&Value>,
+    ) {
+        let mappings = match mappings {
+            None => return,
+            Some(Value::Dict(_, mappings)) => mappings,
+            Some(_) => {
+                self.report(
+                    Severity::Error,
+                    layer,
+                    None,
+                    None,
+                    format!("`{}` must be a table", table),
+                );
+                return;
+            }
+        };
+        for (key, value) in mappings {
+            let locate = |token: Option<&str>| source.and_then(|s| s.locate(table, key, token));
+            let usage = match hid::parse_usage(key) {
+                Ok(usage) => usage,
+                Err(err) => {
+                    let message = match err {
+                        UsageError::Unknown(_) => {
+                            format!("{} (mapping keys are scancodes or HID usage names)", err)
+                        }
+                        UsageError::Invalid(..) => {
+                            format!("{} (scancodes go from 0 to 255)", err)
+                        }
+                    };
+                    self.report(Severity::Error, layer, source, locate(None), message);
+                    continue;
+                }
+            };
+            self.mapped
+                .entry((table.to_string(), usage))
+                .or_default()
+                .push((layer.name.clone(), key.clone()));
+            self.check_mapping(layer, source, table, key, usage, value);
+        }
+    }
+
+    fn check_mapping(
+        &mut self,
+        layer: &Layer,
+        source: Option<&Source>,
+        table: &str,
+        key: &str,
+        usage: u8,
+        value: &Value,
+    ) {
+        let locate = |token: Option<&str>| source.and_then(|s| s.locate(table, key, token));
+        let mut valid = true;
+        match value.find_ref("keys") {
+            Some(Value::String(_, combo)) => {
+                if let Err(err) = combo.parse::<KeySequence>() {
+                    let position = locate(Some(&format!("\"{}\"", combo)))
+                        .map(|(line, col)| (line, col + err.column));
+                    self.report(
+                        Severity::Error,
+                        layer,
+                        source,
+                        position,
+                        format!("{} in \"{}\"", err.error, combo),
+                    );
+                    valid = false;
+                }
+            }
+            Some(Value::Array(_, keys)) => {
+                for key_value in keys {
+                    if let Err(err) = key_value.deserialize::<VK>() {
+                        let token = key_value.as_str().map(|name| format!("\"{}\"", name));
+                        self.report(
+                            Severity::Error,
+                            layer,
+                            source,
+                            locate(token.as_deref()),
+                            err.kind.to_string(),
+                        );
+                        valid = false;
+                    }
+                }
+            }
+            _ => {}
+        }
+        if is_zero_rate(value.find_ref("repeat")) {
+            self.report(
+                Severity::Error,
+                layer,
+                source,
+                locate(Some("rate_ms")),
+                "`repeat.rate_ms` must be at least 1".to_string(),
+            );
+            valid = false;
+        }
+        if !valid {
+            return;
+        }
+        match value.deserialize::<MappingAction>() {
+            Err(err) => {
+                self.report(
+                    Severity::Error,
+                    layer,
+                    source,
+                    locate(None),
+                    err.kind.to_string(),
+                );
+            }
+            Ok(MappingAction::Keys { keys, .. }) => {
+                if let Some(vk) = hid::usage_vk(usage) {
+                    if keys.keys().any(|k| k.code() == vk) {
+                        self.report(
+                            Severity::Warning,
+                            layer,
+                            source,
+                            locate(Some("keys")),
+                            format!(
+                                "{} is mapped to a combination that includes itself, Windows \
+                                 already sends this key",
+                                key
+                            ),
+                        );
+                    }
+                }
+            }
+            Ok(MappingAction::Program { path, .. }) => {
+                if !program_exists(&path) {
+                    self.report(
+                        Severity::Error,
+                        layer,
+                        source,
+                        locate(Some(&path)),
+                        format!("Program not found: {}", path),
+                    )
+                    .external = true;
+                }
+            }
+            Ok(MappingAction::Mouse { .. }) => {}
+        }
+    }
+
+    /// Warns about the addresses that don't tell the hubs apart when several are captured, since
+    /// each hub numbers its devices on its own.
+    fn check_hubs(&mut self, path: &Path, config: &Config) {
+        let several = match &config.general.driver {
+            Driver::List(drivers) => drivers.iter().collect::<HashSet<_>>().len() > 1,
+            Driver::Mode(DriverMode::All) => true,
+            Driver::Number(_) | Driver::Mode(DriverMode::Auto) => false,
+        };
+        if !several {
+            return;
+        }
+        let mut warn = |message: String| {
+            self.problems.push(Problem {
+                severity: Severity::Warning,
+                location: path.display().to_string(),
+                message,
+                external: false,
+            })
+        };
+        if let Capture::Addresses(_) = config.general.capture {
+            warn("`general.capture` addresses apply to every captured hub".to_string());
+        }
+        for (name, device) in &config.devices {
+            if device.address.is_some() && device.bus.is_none() {
+                warn(format!(
+                    "`devices.{}` has an address but no bus, it matches the devices with that \
+                     address on every captured hub",
+                    name
+                ));
+            }
+        }
+    }
+
+    fn check_duplicates(&mut self) {
+        let mut duplicates: Vec<_> = self
+            .mapped
+            .iter()
+            .filter(|(_, places)| places.len() > 1)
+            .collect();
+        duplicates.sort_by_key(|(key, _)| *key);
+        let mut problems = Vec::new();
+        for ((table, usage), places) in duplicates {
+            let same_layer = places
+                .iter()
+                .enumerate()
+                .any(|(i, (layer, _))| places[..i].iter().any(|(other, _)| other == layer));
+            let list = places
+                .iter()
+                .map(|(layer, key)| format!("`{}` in {}", key, layer))
+                .collect::<Vec<_>>()
+                .join(", ");
+            problems.push(if same_layer {
+                Problem {
+                    severity: Severity::Error,
+                    location: places[0].0.clone(),
+                    message: format!(
+                        "Scancode {} is mapped more than once in `{}`: {}",
+                        usage, table, list
+                    ),
+                    external: false,
+                }
+            } else {
+                Problem {
+                    severity: Severity::Warning,
+                    location: places[0].0.clone(),
+                    message: format!(
+                        "Scancode {} of `{}` is mapped in several layers, the mapping in {} \
+                         replaces the others: {}",
+                        usage,
+                        table,
+                        places[places.len() - 1].0,
+                        list
+                    ),
+                    external: false,
+                }
+            });
+        }
+        self.problems.extend(problems);
+    }
+}
+
+/// Whether `repeat` has a zero `rate_ms`, which the untagged `Repeat` only reports as not
+/// matching any variant.
+fn is_zero_rate(repeat: Option<&Value>) -> bool {
+    repeat
+        .and_then(|repeat| repeat.find_ref("rate_ms"))
+        .and_then(Value::to_u128)
+        == Some(0)
+}
+
+/// Whether `path` is a file, or a program found through `PATH`.
+fn program_exists(path: &str) -> bool {
+    let path = Path::new(path);
+    if path.is_file() {
+        return true;
+    }
+    if path.components().count() > 1 {
+        return false;
+    }
+    let extensions = env::var("PATHEXT").unwrap_or_else(|_| ".EXE;.BAT;.CMD;.COM".to_string());
+    env::var_os("PATH")
+        .map(|dirs| {
+            env::split_paths(&dirs).any(|dir| {
+                let candidate = dir.join(path);
+                candidate.is_file()
+                    || extensions.split(';').any(|ext| {
+                        candidate
+                            .with_extension(ext.trim_start_matches('.'))
+                            .is_file()
+                    })
+            })
+        })
+        .unwrap_or(false)
+}
+
+/// Checks every layer of the config, returning all the problems found.
+pub fn check(path: &Path, driver: Option<u8>) -> Vec<Problem> {
+    let mut checker = Checker::default();
+    match Config::layers(path, driver) {
+        Ok(layers) => {
+            for layer in layers {
+                checker.check_layer(&layer);
+            }
+        }
+        Err(err) => checker.problems.push(Problem {
+            severity: Severity::Error,
+            location: path.display().to_string(),
+            message: err.to_string(),
+            external: false,
+        }),
+    }
+    checker.check_duplicates();
+    // the config may still be loaded with external problems
+    if !checker.problems.iter().any(Problem::prevents_loading) {
+        match Config::load(path, driver) {
+            Ok(config) => checker.check_hubs(path, &config),
+            Err(err) => checker.problems.push(Problem {
+                severity: Severity::Error,
+                location: path.display().to_string(),
+                message: err.to_string(),
+                external: false,
+            }),
+        }
+    }
+    checker.problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{indented_keys, toml_keys, Checker, Severity, Source};
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(str::to_string).collect()
+    }
+
+    fn keys(keys: &str) -> Option<Vec<String>> {
+        Some(keys.split('.').map(str::to_string).collect())
+    }
+
+    #[test]
+    fn finds_the_keys_of_toml_lines() {
+        let text = r#"
+[mappings.116]
+type = "keys"
+keys = [
+  "CONTROL",
+]
+[mappings]
+117 = { type = "keys", keys = "Ctrl+C" }
+118.type = "keys"
+[devices."my.pad".mappings.F13]
+'keys' = "A" # comment
+"#;
+        assert_eq!(
+            toml_keys(&lines(text)),
+            vec![
+                None,
+                keys("mappings.116"),
+                keys("mappings.116.type"),
+                keys("mappings.116.keys"),
+                None,
+                None,
+                keys("mappings"),
+                keys("mappings.117"),
+                keys("mappings.118.type"),
+                Some(vec![
+                    "devices".to_string(),
+                    "my.pad".to_string(),
+                    "mappings".to_string(),
+                    "F13".to_string(),
+                ]),
+                Some(vec![
+                    "devices".to_string(),
+                    "my.pad".to_string(),
+                    "mappings".to_string(),
+                    "F13".to_string(),
+                    "keys".to_string(),
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_the_keys_of_indented_lines() {
+        let yaml = "mappings:\n  116:\n    keys: Ctrl+A\n  \"117\": {}\ngeneral:\n  driver: 1\n";
+        assert_eq!(
+            indented_keys(&lines(yaml)),
+            vec![
+                keys("mappings"),
+                keys("mappings.116"),
+                keys("mappings.116.keys"),
+                keys("mappings.117"),
+                keys("general"),
+                keys("general.driver"),
+            ]
+        );
+        let json = "{\n  \"mappings\": {\n    \"116\": {\n      \"keys\": [\n        \"A\"\n      ]\n    }\n  }\n}\n";
+        assert_eq!(
+            indented_keys(&lines(json)),
+            vec![
+                None,
+                keys("mappings"),
+                keys("mappings.116"),
+                keys("mappings.116.keys"),
+                None,
+                None,
+                None,
+                None,
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn locates_toml_mappings() {
+        let source = Source::parse(
+            r#"[mappings]
+116 = { type = "keys", keys = "Ctrl+Q" }
+117.type = "keys"
+117.keys = "Foo"
+
+[mappings.118]
+type = "keys"
+keys = [
+  "CONTROL",
+  "X",
+]
+
+[devices."pad".mappings.F13]
+keys = "Bad"
+"#,
+            false,
+        );
+        assert_eq!(source.locate("mappings", "116", None), Some((2, 1)));
+        assert_eq!(
+            source.locate("mappings", "116", Some("\"Ctrl+Q\"")),
+            Some((2, 31))
+        );
+        assert_eq!(
+            source.locate("mappings", "117", Some("\"Foo\"")),
+            Some((4, 12))
+        );
+        assert_eq!(
+            source.locate("mappings", "118", Some("\"X\"")),
+            Some((10, 3))
+        );
+        // not in the mapping, so the mapping itself
+        assert_eq!(source.locate("mappings", "118", Some("Bad")), Some((6, 1)));
+        assert_eq!(
+            source.locate("devices.pad.mappings", "F13", Some("\"Bad\"")),
+            Some((14, 8))
+        );
+        assert_eq!(source.locate("mappings", "119", None), None);
+    }
+
+    #[test]
+    fn locates_indented_mappings() {
+        let yaml = Source::parse(
+            "mappings:\n  116:\n    keys: Ctrl+A\ndevices:\n  pad:\n    mappings:\n      116:\n        keys: \"Bad\"\n",
+            true,
+        );
+        assert_eq!(
+            yaml.locate("devices.pad.mappings", "116", Some("\"Bad\"")),
+            Some((8, 15))
+        );
+        assert_eq!(
+            yaml.locate("mappings", "116", Some("\"Bad\"")),
+            Some((2, 3))
+        );
+        let json = Source::parse(
+            "{\n  \"mappings\": {\n    \"116\": { \"keys\": \"A\" }\n  },\n  \"devices\": {\n    \"pad\": {\n      \"mappings\": {\n        \"116\": {\n          \"keys\": \"B\"\n        }\n      }\n    }\n  }\n}\n",
+            true,
+        );
+        assert_eq!(
+            json.locate("devices.pad.mappings", "116", Some("\"B\"")),
+            Some((9, 19))
+        );
+        assert_eq!(json.locate("mappings", "116", Some("\"A\"")), Some((3, 22)));
+    }
+
+    #[test]
+    fn reports_duplicates() {
+        let mut checker = Checker::default();
+        let place = |layer: &str, key: &str| (layer.to_string(), key.to_string());
+        checker.mapped.insert(
+            ("mappings".to_string(), 0x76),
+            vec![
+                place("usbrawmap.toml", "118"),
+                place("usbrawmap.toml", "Props"),
+            ],
+        );
+        checker.mapped.insert(
+            ("mappings".to_string(), 0x74),
+            vec![
+                place("preset sun-type6", "Open"),
+                place("usbrawmap.toml", "116"),
+            ],
+        );
+        checker.mapped.insert(
+            ("mappings".to_string(), 0x75),
+            vec![place("usbrawmap.toml", "Help")],
+        );
+        checker.check_duplicates();
+        assert_eq!(checker.problems.len(), 2);
+        let across = &checker.problems[0];
+        assert!(across.severity == Severity::Warning);
+        assert!(across
+            .message
+            .contains("the mapping in usbrawmap.toml replaces the others"));
+        let same = &checker.problems[1];
+        assert!(same.severity == Severity::Error);
+        assert!(same
+            .message
+            .contains("`118` in usbrawmap.toml, `Props` in usbrawmap.toml"));
+    }
+}
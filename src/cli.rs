@@ -0,0 +1,112 @@
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context};
+
+pub const CONFIG_FILE_NAME: &str = "usbrawmap.toml";
+
+pub const USAGE: &str = "\
+Usage: usbrawmap [COMMAND] [OPTIONS]
+
+Commands:
+  run           Remap the keys (default)
+  learn         Print the scancodes of the keys as they are pressed
+  check         Check the config file (or the one given after it) and report every problem
+  list-devices  Print the devices plugged into each USBPcap hub
+
+Options:
+  -c, --config <PATH>  Config file to use
+  -d, --driver <N>     USBPcap hub to capture from, overriding general.driver
+  -h, --help           Print this message";
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Learn,
+    Check,
+    ListDevices,
+}
+
+pub struct Args {
+    pub command: Command,
+    pub config: Option<PathBuf>,
+    pub driver: Option<u8>,
+    pub help: bool,
+}
+
+impl Args {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> anyhow::Result<Args> {
+        let mut res = Args {
+            command: Command::Run,
+            config: None,
+            driver: None,
+            help: false,
+        };
+        let mut command = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| anyhow!("Missing value for {}", name))
+            };
+            match arg.as_str() {
+                "-c" | "--config" => res.config = Some(value(&arg)?.into()),
+                "-d" | "--driver" => {
+                    let driver = value(&arg)?;
+                    res.driver = Some(
+                        driver
+                            .parse()
+                            .with_context(|| format!("Invalid driver number: {}", driver))?,
+                    );
+                }
+                "-h" | "--help" => res.help = true,
+                _ if arg.starts_with('-') => bail!("Unknown option: {}", arg),
+                _ if command == Some(Command::Check) && res.config.is_none() => {
+                    res.config = Some(arg.into())
+                }
+                _ if command.is_some() => bail!("Unexpected argument: {}", arg),
+                "run" => command = Some(Command::Run),
+                "learn" => command = Some(Command::Learn),
+                "check" => command = Some(Command::Check),
+                "list-devices" => command = Some(Command::ListDevices),
+                _ => bail!("Unknown command: {}", arg),
+            }
+        }
+        if let Some(command) = command {
+            res.command = command;
+        }
+        Ok(res)
+    }
+
+    /// The config file given on the command line, or the first one found in the working
+    /// directory, the executable's directory and the user's config directory.
+    pub fn config_path(&self) -> anyhow::Result<PathBuf> {
+        if let Some(path) = &self.config {
+            return Ok(path.clone());
+        }
+        let candidates = [
+            Some(PathBuf::from(CONFIG_FILE_NAME)),
+            env::current_exe()
+                .ok()
+                .and_then(|exe| exe.parent().map(|dir| dir.join(CONFIG_FILE_NAME))),
+            env::var_os("APPDATA")
+                .map(|dir| PathBuf::from(dir).join("usbrawmap").join(CONFIG_FILE_NAME)),
+        ];
+        candidates
+            .iter()
+            .flatten()
+            .find(|path| path.is_file())
+            .cloned()
+            .ok_or_else(|| {
+                anyhow!(
+                    "No config file found, looked for {}",
+                    candidates
+                        .iter()
+                        .flatten()
+                        .map(|path| path.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
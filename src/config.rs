@@ -0,0 +1,428 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use figment::providers::{Env, Format, Json, Serialized, Toml, Yaml};
+use figment::Figment;
+use serde::{Deserialize, Deserializer};
+
+use crate::device::{DeviceAddress, DeviceIds};
+use crate::mappings::{Mapping, MappingAction};
+use crate::vk::{Injection, Repeat};
+use crate::Error;
+use crate::{hid, presets};
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub general: GeneralConfig,
+    /// Filled layer by layer, see `LayerMappings`.
+    #[serde(skip)]
+    pub mappings: MappingConfig,
+    #[serde(default)]
+    pub devices: BTreeMap<String, DeviceConfig>,
+}
+
+#[derive(Deserialize)]
+pub struct GeneralConfig {
+    pub driver: Driver,
+    #[serde(default)]
+    pub max_processes: Option<usize>,
+    #[serde(default)]
+    pub repeat: Option<Repeat>,
+    #[serde(default)]
+    pub injection: Injection,
+    /// Ignore the devices that don't match any `[devices]` section, instead of using the
+    /// top-level `[mappings]` for them.
+    #[serde(default)]
+    pub ignore_other_devices: bool,
+    #[serde(default)]
+    pub capture: Capture,
+    /// Show the failures of the mappings in a message box, not only in the console.
+    #[serde(default)]
+    pub notify_failures: bool,
+}
+
+/// `driver = "auto" | "all" | n | [n, ...]`, the USBPcap control devices to capture from.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Driver {
+    Number(u8),
+    List(Vec<u8>),
+    Mode(DriverMode),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DriverMode {
+    /// The hub where a device of a `[devices]` section is plugged.
+    Auto,
+    /// Every hub.
+    All,
+}
+
+/// `capture = "auto" | "all" | "detect" | [addresses]`
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Capture {
+    Mode(CaptureMode),
+    Addresses(Vec<u8>),
+}
+
+impl Default for Capture {
+    fn default() -> Self {
+        Capture::Mode(CaptureMode::Auto)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureMode {
+    Auto,
+    All,
+    /// The first keyboard a key is pressed on.
+    Detect,
+}
+
+/// The devices to capture the traffic of, so that the driver doesn't buffer the traffic of
+/// the other devices on the hub.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum CaptureFilter {
+    All,
+    Addresses(Vec<u8>),
+    /// Every device until one sends a keyboard report, then only that one.
+    Detect,
+}
+
+/// Mappings for the devices matching all the given criteria. They take precedence over the
+/// top-level `[mappings]`.
+#[derive(Deserialize)]
+pub struct DeviceConfig {
+    #[serde(default)]
+    pub vid: Option<u16>,
+    #[serde(default)]
+    pub pid: Option<u16>,
+    /// The USBPcap hub the device is plugged into, `n` in `\\.\USBPcapn`, since addresses
+    /// are only unique on a hub.
+    #[serde(default)]
+    pub bus: Option<u16>,
+    /// The device address on the hub, as printed by `usbrawmap learn`.
+    #[serde(default)]
+    pub address: Option<u16>,
+    #[serde(skip)]
+    pub mappings: MappingConfig,
+}
+
+impl DeviceConfig {
+    /// Whether the section gives a VID or PID, and they match `ids`.
+    pub fn matches_ids(&self, ids: DeviceIds) -> bool {
+        (self.vid.is_some() || self.pid.is_some())
+            && self.vid.is_none_or(|vid| ids.vid == vid)
+            && self.pid.is_none_or(|pid| ids.pid == pid)
+    }
+
+    pub fn matches(&self, address: DeviceAddress, ids: Option<DeviceIds>) -> bool {
+        if self.vid.is_none() && self.pid.is_none() && self.bus.is_none() && self.address.is_none()
+        {
+            return false;
+        }
+        self.bus.is_none_or(|bus| bus == address.bus)
+            && self.address.is_none_or(|a| a == address.address)
+            && self
+                .vid
+                .is_none_or(|vid| ids.is_some_and(|ids| ids.vid == vid))
+            && self
+                .pid
+                .is_none_or(|pid| ids.is_some_and(|ids| ids.pid == pid))
+    }
+}
+
+#[derive(Default)]
+pub struct MappingConfig(pub HashMap<u8, Mapping>);
+
+impl<'de> Deserialize<'de> for MappingConfig {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let mut mappings = HashMap::new();
+        for (key, action) in HashMap::<String, MappingAction>::deserialize(deserializer)? {
+            let usage = hid::parse_usage(&key).map_err(serde::de::Error::custom)?;
+            // e.g. `118` and `Props`, which would otherwise win at random
+            if mappings.insert(usage, Mapping(usage, action)).is_some() {
+                return Err(serde::de::Error::custom(format!(
+                    "scancode {} is mapped more than once",
+                    usage
+                )));
+            }
+        }
+        Ok(MappingConfig(mappings))
+    }
+}
+
+/// The mappings of a single layer. Mappings aren't merged through figment, which would mix the
+/// fields of a mapping with the ones it overrides, and keep a key spelled differently in two
+/// layers as two mappings. Instead, the mappings of a layer replace the ones of the previous
+/// layers with the same scancode.
+#[derive(Deserialize)]
+struct LayerMappings {
+    #[serde(default)]
+    mappings: MappingConfig,
+    #[serde(default)]
+    devices: BTreeMap<String, DeviceMappings>,
+}
+
+#[derive(Deserialize)]
+struct DeviceMappings {
+    #[serde(default)]
+    mappings: MappingConfig,
+}
+
+/// One of the sources the config is merged from.
+pub struct Layer {
+    pub name: String,
+    /// The file the layer comes from, if any.
+    pub path: Option<PathBuf>,
+    pub figment: Figment,
+}
+
+/// How deep includes can be nested.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+impl Layer {
+    fn file(path: PathBuf) -> Layer {
+        // figment looks for relative paths in the parent directories too, and treats a missing
+        // file as empty
+        let resolved = std::path::absolute(&path).unwrap_or_else(|_| path.clone());
+        let figment = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Figment::from(Json::file(&resolved)),
+            Some("yaml" | "yml") => Figment::from(Yaml::file(&resolved)),
+            _ => Figment::from(Toml::file(&resolved)),
+        };
+        Layer {
+            name: path.display().to_string(),
+            path: Some(path),
+            figment,
+        }
+    }
+
+    /// What identifies the layer in a chain of includes: its canonical path, or its name for
+    /// a preset.
+    fn identity(&self) -> String {
+        match &self.path {
+            Some(path) => fs::canonicalize(path)
+                .unwrap_or_else(|_| path.clone())
+                .display()
+                .to_string(),
+            None => self.name.clone(),
+        }
+    }
+
+    /// A built-in preset, or a file relative to `dir`.
+    fn include(name: &str, dir: &Path) -> Result<Layer, Error> {
+        if let Some(content) = presets::get(name) {
+            return Ok(Layer {
+                name: format!("preset {}", name),
+                path: None,
+                figment: Figment::from(Toml::string(content)),
+            });
+        }
+        let path = dir.join(name);
+        if path.is_file() {
+            Ok(Layer::file(path))
+        } else {
+            Err(Error::Include(name.to_string()))
+        }
+    }
+
+    /// Pushes the layer, after the layers it includes so that it overrides them. `chain` holds
+    /// the identity and name of the layers including this one.
+    fn push_with_includes(
+        self,
+        layers: &mut Vec<Layer>,
+        dir: &Path,
+        chain: &mut Vec<(String, String)>,
+    ) -> Result<(), Error> {
+        // a file that fails to parse has no includes, the error is reported when loading it
+        let includes = match self.figment.find_value("include") {
+            Ok(value) => value
+                .deserialize::<Vec<String>>()
+                .map_err(|err| Error::Config(Box::new(err)))?,
+            Err(_) => Vec::new(),
+        };
+        chain.push((self.identity(), self.name.clone()));
+        for name in includes {
+            if chain.len() > MAX_INCLUDE_DEPTH {
+                return Err(Error::Include(format!("{} (nested too deep)", name)));
+            }
+            let layer = Layer::include(&name, dir)?;
+            let identity = layer.identity();
+            if let Some(start) = chain.iter().position(|(other, _)| *other == identity) {
+                let names: Vec<&str> = chain[start..]
+                    .iter()
+                    .map(|(_, name)| name.as_str())
+                    .chain([layer.name.as_str()])
+                    .collect();
+                return Err(Error::IncludeCycle(names.join(" -> ")));
+            }
+            let dir = match &layer.path {
+                Some(path) => path.parent().unwrap_or(dir).to_path_buf(),
+                None => dir.to_path_buf(),
+            };
+            layer.push_with_includes(layers, &dir, chain)?;
+        }
+        chain.pop();
+        layers.push(self);
+        Ok(())
+    }
+}
+
+impl Config {
+    /// The mapping for a key pressed on the given device.
+    pub fn mapping(
+        &self,
+        address: DeviceAddress,
+        ids: Option<DeviceIds>,
+        key: u8,
+    ) -> Option<&Mapping> {
+        match self
+            .devices
+            .values()
+            .find(|device| device.matches(address, ids))
+        {
+            Some(device) => device
+                .mappings
+                .0
+                .get(&key)
+                .or_else(|| self.mappings.0.get(&key)),
+            None if self.general.ignore_other_devices => None,
+            None => self.mappings.0.get(&key),
+        }
+    }
+
+    /// The devices to capture on a hub: the ones given by `capture`, which apply to every hub,
+    /// or with `auto` the devices of the `[devices]` sections on the hub when they all have an
+    /// address and the other devices are ignored, and every device otherwise. Detecting the
+    /// keyboard is opt-in, since a key pressed on another keyboard of the hub first would hide
+    /// the mapped one until restarting.
+    pub fn capture_filter(&self, driver: u8) -> CaptureFilter {
+        match &self.general.capture {
+            Capture::Mode(CaptureMode::All) => CaptureFilter::All,
+            Capture::Mode(CaptureMode::Detect) => CaptureFilter::Detect,
+            Capture::Addresses(addresses) => CaptureFilter::Addresses(addresses.clone()),
+            Capture::Mode(CaptureMode::Auto) => {
+                if self.devices.is_empty() || !self.general.ignore_other_devices {
+                    return CaptureFilter::All;
+                }
+                self.devices
+                    .values()
+                    .filter(|device| device.bus.is_none_or(|bus| bus == u16::from(driver)))
+                    .map(|device| device.address.and_then(|a| u8::try_from(a).ok()))
+                    .collect::<Option<Vec<_>>>()
+                    .map_or(CaptureFilter::All, CaptureFilter::Addresses)
+            }
+        }
+    }
+
+    /// The number of mappings, across all devices.
+    pub fn mapping_count(&self) -> usize {
+        self.mappings.0.len()
+            + self
+                .devices
+                .values()
+                .map(|device| device.mappings.0.len())
+                .sum::<usize>()
+    }
+
+    /// The layers the config is merged from, in increasing order of priority: the built-in
+    /// defaults, the file at `path` (TOML, JSON or YAML depending on its extension), the JSON
+    /// and YAML files with the same name if there are some, `USBRAWMAP_*` environment variables
+    /// (with `__` between keys, e.g. `USBRAWMAP_GENERAL__DRIVER`), and the driver given on the
+    /// command line. The presets and files listed in a file's `include` come right before it.
+    pub fn layers(path: &Path, driver: Option<u8>) -> Result<Vec<Layer>, Error> {
+        if !path.is_file() {
+            return Err(Error::ConfigFile(path.display().to_string()));
+        }
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut layers = vec![Layer {
+            name: "defaults".to_string(),
+            path: None,
+            figment: Figment::from(Serialized::default("general.driver", "auto")),
+        }];
+        Layer::file(path.to_path_buf()).push_with_includes(&mut layers, dir, &mut Vec::new())?;
+        for other in Config::variants(path) {
+            if other.is_file() {
+                Layer::file(other).push_with_includes(&mut layers, dir, &mut Vec::new())?;
+            }
+        }
+        layers.push(Layer {
+            name: "environment".to_string(),
+            path: None,
+            figment: Figment::from(Env::prefixed("USBRAWMAP_").split("__")),
+        });
+        if let Some(driver) = driver {
+            layers.push(Layer {
+                name: "command line".to_string(),
+                path: None,
+                figment: Figment::from(Serialized::default("general.driver", driver)),
+            });
+        }
+        Ok(layers)
+    }
+
+    /// The JSON and YAML files with the same name as the config file.
+    fn variants(path: &Path) -> Vec<PathBuf> {
+        ["json", "yaml", "yml"]
+            .iter()
+            .map(|ext| path.with_extension(ext))
+            .filter(|other| other != path)
+            .collect()
+    }
+
+    /// The files the config is loaded from, including the ones that don't exist yet but would
+    /// be loaded if they did.
+    pub fn files(path: &Path) -> Vec<PathBuf> {
+        let mut files = vec![path.to_path_buf()];
+        files.extend(Config::variants(path));
+        if let Ok(layers) = Config::layers(path, None) {
+            for layer in layers.into_iter().filter_map(|layer| layer.path) {
+                if !files.contains(&layer) {
+                    files.push(layer);
+                }
+            }
+        }
+        files
+    }
+
+    pub fn load(path: &Path, driver: Option<u8>) -> Result<Config, Error> {
+        let layers = Config::layers(path, driver)?;
+        let mut config: Config = layers
+            .iter()
+            .fold(Figment::new(), |figment, layer| {
+                figment.merge(layer.figment.clone())
+            })
+            .extract()
+            .map_err(|err| Error::Config(Box::new(err)))?;
+        if let Capture::Addresses(addresses) = &config.general.capture {
+            if let Some(address) = addresses.iter().find(|a| !(1..=127).contains(*a)) {
+                return Err(Error::Config(Box::new(figment::Error::from(format!(
+                    "invalid address {} in `general.capture`, addresses go from 1 to 127",
+                    address
+                )))));
+            }
+        }
+        for layer in &layers {
+            let layer: LayerMappings = layer
+                .figment
+                .extract()
+                .map_err(|err| Error::Config(Box::new(err)))?;
+            config.mappings.0.extend(layer.mappings.0);
+            for (name, device) in layer.devices {
+                // the merged config has the devices of every layer
+                if let Some(config_device) = config.devices.get_mut(&name) {
+                    config_device.mappings.0.extend(device.mappings.0);
+                }
+            }
+        }
+        Ok(config)
+    }
+}
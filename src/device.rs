@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use pnet_packet::usbpcap::UsbPcapPacket;
+
+use crate::report;
+
+const URB_CONTROL: u8 = 0x02;
+const DIRECTION_IN: u8 = 1;
+const USB_DEVICE_DESCRIPTOR_TYPE: u8 = 0x01;
+const USB_DEVICE_DESCRIPTOR_LENGTH: u8 = 18;
+
+/// Where a device is plugged, as seen by USBPcap.
+#[derive(Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct DeviceAddress {
+    pub bus: u16,
+    pub address: u16,
+}
+
+impl DeviceAddress {
+    pub fn of(packet: &UsbPcapPacket) -> Self {
+        DeviceAddress {
+            bus: packet.get_bus(),
+            address: packet.get_device(),
+        }
+    }
+}
+
+impl fmt::Display for DeviceAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bus {} device {}", self.bus, self.address)
+    }
+}
+
+/// One of the HID interfaces of a device. USBPcap doesn't give the interface number of
+/// interrupt transfers, but each interface has its own IN endpoint, which identifies it.
+#[derive(Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Interface {
+    pub device: DeviceAddress,
+    pub endpoint: u8,
+}
+
+impl Interface {
+    pub fn of(packet: &UsbPcapPacket) -> Self {
+        Interface {
+            device: DeviceAddress::of(packet),
+            endpoint: packet.get_endpoint(),
+        }
+    }
+}
+
+impl fmt::Display for Interface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} endpoint {}", self.device, self.endpoint)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct DeviceIds {
+    pub vid: u16,
+    pub pid: u16,
+}
+
+impl fmt::Display for DeviceIds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vid, self.pid)
+    }
+}
+
+/// Reads the vendor and product IDs from a device descriptor.
+pub fn parse_device_descriptor(data: &[u8]) -> Option<DeviceIds> {
+    if data.len() < USB_DEVICE_DESCRIPTOR_LENGTH as usize
+        || data[0] != USB_DEVICE_DESCRIPTOR_LENGTH
+        || data[1] != USB_DEVICE_DESCRIPTOR_TYPE
+    {
+        return None;
+    }
+    Some(DeviceIds {
+        vid: u16::from_le_bytes([data[8], data[9]]),
+        pid: u16::from_le_bytes([data[10], data[11]]),
+    })
+}
+
+/// The IDs of the devices, read from the hub when it is opened and learnt from the
+/// GET_DESCRIPTOR responses seen in the capture, which devices only send when plugged in.
+#[derive(Default)]
+pub struct DeviceTable(HashMap<DeviceAddress, DeviceIds>);
+
+impl DeviceTable {
+    /// Records the IDs if the packet is a device descriptor, returning them when they are new.
+    pub fn learn(&mut self, packet: &UsbPcapPacket) -> Option<(DeviceAddress, DeviceIds)> {
+        if packet.get_transfer() != URB_CONTROL
+            || packet.get_direction() != DIRECTION_IN
+            || packet.get_pdo_to_fdo() != 1
+        {
+            return None;
+        }
+        let ids = parse_device_descriptor(report::payload(packet).ok()?)?;
+        let address = DeviceAddress::of(packet);
+        match self.0.insert(address, ids) {
+            Some(old) if old == ids => None,
+            _ => Some((address, ids)),
+        }
+    }
+
+    pub fn insert(&mut self, address: DeviceAddress, ids: DeviceIds) {
+        self.0.insert(address, ids);
+    }
+
+    pub fn get(&self, address: DeviceAddress) -> Option<DeviceIds> {
+        self.0.get(&address).copied()
+    }
+}
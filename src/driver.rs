@@ -1,21 +1,23 @@
 use std::ffi::c_void;
 use std::io::Read;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{io, ptr};
 use widestring::WideCString;
 
 use windows::core::{Error as WinError, PCWSTR};
-use windows::Win32::Foundation::{CloseHandle, BOOLEAN, HANDLE};
+use windows::Win32::Foundation::{CloseHandle, ERROR_OPERATION_ABORTED, HANDLE};
 use windows::Win32::Storage::FileSystem::{
     CreateFileW, ReadFile, FILE_FLAGS_AND_ATTRIBUTES, FILE_GENERIC_READ, FILE_GENERIC_WRITE,
     FILE_SHARE_NONE, OPEN_EXISTING,
 };
-use windows::Win32::System::IO::DeviceIoControl;
+use windows::Win32::System::IO::{CancelIoEx, DeviceIoControl};
 
 use crate::{IoctlMessage, SetupBuffer, StartFiltering, DEFAULT_INTERNAL_KERNEL_BUFFER_SIZE};
 
 pub struct UsbPcapDriver {
     handle: HANDLE,
+    cancelled: AtomicBool,
 }
 
 impl Drop for UsbPcapDriver {
@@ -27,7 +29,7 @@ impl Drop for UsbPcapDriver {
 }
 
 impl UsbPcapDriver {
-    pub fn new(filename: &Path) -> Result<UsbPcapDriver, WinError> {
+    pub fn new(filename: &Path, filter: StartFiltering) -> Result<UsbPcapDriver, WinError> {
         let filename = unsafe { WideCString::from_os_str_unchecked(filename.as_os_str()) };
         let handle = unsafe {
             CreateFileW(
@@ -41,13 +43,13 @@ impl UsbPcapDriver {
             )
         }?;
 
-        let res = UsbPcapDriver { handle };
+        let res = UsbPcapDriver {
+            handle,
+            cancelled: AtomicBool::new(false),
+        };
 
         res.reset_buffer()?;
-        res.ioctl(StartFiltering {
-            addresses: [0, 0, 0, 0],
-            filter_all: BOOLEAN(1),
-        })?;
+        res.set_filter(filter)?;
 
         Ok(res)
     }
@@ -58,6 +60,19 @@ impl UsbPcapDriver {
         })
     }
 
+    /// Makes a read blocked on another thread fail, and the next ones, to stop the capture.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        unsafe {
+            let _ = CancelIoEx(self.handle, ptr::null());
+        }
+    }
+
+    /// Changes the devices captured, while the capture is running.
+    pub fn set_filter(&self, filter: StartFiltering) -> Result<(), WinError> {
+        self.ioctl(filter)
+    }
+
     fn ioctl<T: IoctlMessage>(&self, ioctl_message: T) -> Result<(), WinError> {
         let buf = &ioctl_message as *const _ as *mut c_void;
         unsafe {
@@ -76,25 +91,30 @@ impl UsbPcapDriver {
     }
 
     unsafe fn read_internal(&self, buf: &mut [u8]) -> Result<usize, WinError> {
-        let mut read = 0;
-        ReadFile(
-            self.handle,
-            buf.as_mut_ptr() as *mut c_void,
-            buf.len() as u32,
-            &mut read,
-            ptr::null_mut(),
-        )
-        .ok()?;
-        if read == 0 {
+        loop {
+            // a read started after the cancellation wouldn't be cancelled
+            if self.cancelled.load(Ordering::SeqCst) {
+                return Err(ERROR_OPERATION_ABORTED.to_hresult().into());
+            }
+            let mut read = 0;
+            ReadFile(
+                self.handle,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len() as u32,
+                &mut read,
+                ptr::null_mut(),
+            )
+            .ok()?;
+            if read != 0 {
+                return Ok(read as usize);
+            }
             self.reset_buffer()?;
-            self.read_internal(buf)
-        } else {
-            Ok(read as usize)
         }
     }
 }
 
-impl Read for UsbPcapDriver {
+/// Reading through a shared reference lets the filter be changed while a reader owns it.
+impl Read for &UsbPcapDriver {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
         unsafe { self.read_internal(buf) }.map_err(|e| io::Error::from_raw_os_error(e.code().0))
     }
@@ -0,0 +1,223 @@
+use std::num::ParseIntError;
+
+use phf::phf_map;
+use thiserror::Error;
+
+/// Usages of the HID Keyboard/Keypad page, as they appear in boot keyboard reports.
+static USAGES: phf::Map<&str, u8> = phf_map! {
+    "A" => 0x04,
+    "B" => 0x05,
+    "C" => 0x06,
+    "D" => 0x07,
+    "E" => 0x08,
+    "F" => 0x09,
+    "G" => 0x0A,
+    "H" => 0x0B,
+    "I" => 0x0C,
+    "J" => 0x0D,
+    "K" => 0x0E,
+    "L" => 0x0F,
+    "M" => 0x10,
+    "N" => 0x11,
+    "O" => 0x12,
+    "P" => 0x13,
+    "Q" => 0x14,
+    "R" => 0x15,
+    "S" => 0x16,
+    "T" => 0x17,
+    "U" => 0x18,
+    "V" => 0x19,
+    "W" => 0x1A,
+    "X" => 0x1B,
+    "Y" => 0x1C,
+    "Z" => 0x1D,
+    "DIGIT1" => 0x1E,
+    "DIGIT2" => 0x1F,
+    "DIGIT3" => 0x20,
+    "DIGIT4" => 0x21,
+    "DIGIT5" => 0x22,
+    "DIGIT6" => 0x23,
+    "DIGIT7" => 0x24,
+    "DIGIT8" => 0x25,
+    "DIGIT9" => 0x26,
+    "DIGIT0" => 0x27,
+    "ENTER" => 0x28,
+    "ESCAPE" => 0x29,
+    "BACKSPACE" => 0x2A,
+    "TAB" => 0x2B,
+    "SPACE" => 0x2C,
+    "MINUS" => 0x2D,
+    "EQUAL" => 0x2E,
+    "LEFTBRACKET" => 0x2F,
+    "RIGHTBRACKET" => 0x30,
+    "BACKSLASH" => 0x31,
+    "NONUSHASH" => 0x32,
+    "SEMICOLON" => 0x33,
+    "QUOTE" => 0x34,
+    "GRAVE" => 0x35,
+    "COMMA" => 0x36,
+    "PERIOD" => 0x37,
+    "SLASH" => 0x38,
+    "CAPSLOCK" => 0x39,
+    "F1" => 0x3A,
+    "F2" => 0x3B,
+    "F3" => 0x3C,
+    "F4" => 0x3D,
+    "F5" => 0x3E,
+    "F6" => 0x3F,
+    "F7" => 0x40,
+    "F8" => 0x41,
+    "F9" => 0x42,
+    "F10" => 0x43,
+    "F11" => 0x44,
+    "F12" => 0x45,
+    "PRINTSCREEN" => 0x46,
+    "SCROLLLOCK" => 0x47,
+    "PAUSE" => 0x48,
+    "INSERT" => 0x49,
+    "HOME" => 0x4A,
+    "PAGEUP" => 0x4B,
+    "DELETE" => 0x4C,
+    "END" => 0x4D,
+    "PAGEDOWN" => 0x4E,
+    "RIGHT" => 0x4F,
+    "LEFT" => 0x50,
+    "DOWN" => 0x51,
+    "UP" => 0x52,
+    "NUMLOCK" => 0x53,
+    "KPDIVIDE" => 0x54,
+    "KPMULTIPLY" => 0x55,
+    "KPMINUS" => 0x56,
+    "KPPLUS" => 0x57,
+    "KPENTER" => 0x58,
+    "KP1" => 0x59,
+    "KP2" => 0x5A,
+    "KP3" => 0x5B,
+    "KP4" => 0x5C,
+    "KP5" => 0x5D,
+    "KP6" => 0x5E,
+    "KP7" => 0x5F,
+    "KP8" => 0x60,
+    "KP9" => 0x61,
+    "KP0" => 0x62,
+    "KPPERIOD" => 0x63,
+    "NONUSBACKSLASH" => 0x64,
+    "APPLICATION" => 0x65,
+    "POWER" => 0x66,
+    "KPEQUAL" => 0x67,
+    "F13" => 0x68,
+    "F14" => 0x69,
+    "F15" => 0x6A,
+    "F16" => 0x6B,
+    "F17" => 0x6C,
+    "F18" => 0x6D,
+    "F19" => 0x6E,
+    "F20" => 0x6F,
+    "F21" => 0x70,
+    "F22" => 0x71,
+    "F23" => 0x72,
+    "F24" => 0x73,
+    "EXECUTE" => 0x74,
+    "OPEN" => 0x74, // Sun keyboards
+    "HELP" => 0x75,
+    "MENU" => 0x76,
+    "PROPS" => 0x76, // Sun keyboards
+    "SELECT" => 0x77,
+    "FRONT" => 0x77, // Sun keyboards
+    "STOP" => 0x78,
+    "AGAIN" => 0x79,
+    "UNDO" => 0x7A,
+    "CUT" => 0x7B,
+    "COPY" => 0x7C,
+    "PASTE" => 0x7D,
+    "FIND" => 0x7E,
+    "MUTE" => 0x7F,
+    "VOLUMEUP" => 0x80,
+    "VOLUMEDOWN" => 0x81,
+    "KPCOMMA" => 0x85,
+    "INTERNATIONAL1" => 0x87,
+    "INTERNATIONAL2" => 0x88,
+    "INTERNATIONAL3" => 0x89,
+    "INTERNATIONAL4" => 0x8A,
+    "INTERNATIONAL5" => 0x8B,
+    "INTERNATIONAL6" => 0x8C,
+    "INTERNATIONAL7" => 0x8D,
+    "INTERNATIONAL8" => 0x8E,
+    "INTERNATIONAL9" => 0x8F,
+    "LANG1" => 0x90,
+    "LANG2" => 0x91,
+    "LANG3" => 0x92,
+    "LANG4" => 0x93,
+    "LANG5" => 0x94,
+    "LANG6" => 0x95,
+    "LANG7" => 0x96,
+    "LANG8" => 0x97,
+    "LANG9" => 0x98,
+};
+
+#[derive(Error, Debug)]
+pub enum UsageError {
+    #[error("Unknown HID usage: {0}")]
+    Unknown(String),
+    #[error("Invalid HID usage code {0}: {1}")]
+    Invalid(String, ParseIntError),
+}
+
+/// Parses a HID keyboard usage given as a decimal or `0x` hex code, or as a case-insensitive
+/// name from [`USAGES`]. Digit keys are named `DIGIT0` to `DIGIT9` so they can't be mistaken
+/// for codes.
+pub fn parse_usage(s: &str) -> Result<u8, UsageError> {
+    let s = s.trim();
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        return u8::from_str_radix(hex, 16).map_err(|e| UsageError::Invalid(s.to_string(), e));
+    }
+    if s.starts_with(|c: char| c.is_ascii_digit()) {
+        return s.parse().map_err(|e| UsageError::Invalid(s.to_string(), e));
+    }
+    USAGES
+        .get(s.to_ascii_uppercase().as_str())
+        .copied()
+        .ok_or_else(|| UsageError::Unknown(s.to_string()))
+}
+
+/// A name for the usage, for display.
+pub fn usage_name(usage: u8) -> Option<&'static str> {
+    USAGES
+        .entries()
+        .filter(|(_, code)| **code == usage)
+        .map(|(name, _)| *name)
+        .min()
+}
+
+/// The VK Windows' keyboard driver translates the usage to, for the usages it handles.
+pub fn usage_vk(usage: u8) -> Option<u8> {
+    Some(match usage {
+        0x04..=0x1D => 0x41 + (usage - 0x04), // A to Z
+        0x1E..=0x26 => 0x31 + (usage - 0x1E), // 1 to 9
+        0x27 => 0x30,                         // 0
+        0x28 => 0x0D,                         // RETURN
+        0x29 => 0x1B,                         // ESCAPE
+        0x2A => 0x08,                         // BACK
+        0x2B => 0x09,                         // TAB
+        0x2C => 0x20,                         // SPACE
+        0x39 => 0x14,                         // CAPITAL
+        0x3A..=0x45 => 0x70 + (usage - 0x3A), // F1 to F12
+        0x46 => 0x2C,                         // SNAPSHOT
+        0x47 => 0x91,                         // SCROLL
+        0x48 => 0x13,                         // PAUSE
+        0x49 => 0x2D,                         // INSERT
+        0x4A => 0x24,                         // HOME
+        0x4B => 0x21,                         // PRIOR
+        0x4C => 0x2E,                         // DELETE
+        0x4D => 0x23,                         // END
+        0x4E => 0x22,                         // NEXT
+        0x4F => 0x27,                         // RIGHT
+        0x50 => 0x25,                         // LEFT
+        0x51 => 0x28,                         // DOWN
+        0x52 => 0x26,                         // UP
+        0x53 => 0x90,                         // NUMLOCK
+        0x65 => 0x5D,                         // APPS
+        0x68..=0x73 => 0x7C + (usage - 0x68), // F13 to F24
+        _ => return None,
+    })
+}
@@ -1,12 +1,24 @@
 use windows::Win32::Foundation::BOOLEAN;
 use windows::Win32::System::Ioctl::{
-    FILE_DEVICE_UNKNOWN, FILE_READ_ACCESS, FILE_WRITE_ACCESS, METHOD_BUFFERED,
+    FILE_ANY_ACCESS, FILE_DEVICE_UNKNOWN, FILE_READ_ACCESS, FILE_WRITE_ACCESS, METHOD_BUFFERED,
 };
 
 const fn ctl_code(device_type: u32, function: u32, method: u32, access: u32) -> u32 {
     (device_type << 16) | (access << 14) | (function << 2) | method
 }
 
+/// `FILE_DEVICE_USB`, which is the same as `FILE_DEVICE_UNKNOWN`.
+const FILE_DEVICE_USB: u32 = FILE_DEVICE_UNKNOWN;
+
+pub const IOCTL_USBPCAP_GET_HUB_SYMLINK: u32 =
+    ctl_code(FILE_DEVICE_UNKNOWN, 0x803, METHOD_BUFFERED, FILE_ANY_ACCESS);
+pub const IOCTL_USB_GET_NODE_INFORMATION: u32 =
+    ctl_code(FILE_DEVICE_USB, 258, METHOD_BUFFERED, FILE_ANY_ACCESS);
+pub const IOCTL_USB_GET_NODE_CONNECTION_NAME: u32 =
+    ctl_code(FILE_DEVICE_USB, 261, METHOD_BUFFERED, FILE_ANY_ACCESS);
+pub const IOCTL_USB_GET_NODE_CONNECTION_INFORMATION_EX: u32 =
+    ctl_code(FILE_DEVICE_USB, 274, METHOD_BUFFERED, FILE_ANY_ACCESS);
+
 pub trait IoctlMessage {
     const ID: u32;
 }
@@ -27,10 +39,34 @@ impl IoctlMessage for SetupBuffer {
 
 #[repr(C, packed)]
 pub struct StartFiltering {
+    /// One bit per device address, from 0 to 127.
     pub addresses: [u32; 4],
     pub filter_all: BOOLEAN,
 }
 
+impl StartFiltering {
+    /// Captures the traffic of every device on the hub, including the ones plugged later.
+    pub fn all() -> Self {
+        StartFiltering {
+            addresses: [0; 4],
+            filter_all: BOOLEAN(1),
+        }
+    }
+
+    /// Captures the traffic of the devices with the given addresses only. Addresses above 127
+    /// don't exist and are ignored.
+    pub fn addresses(addresses: impl IntoIterator<Item = u8>) -> Self {
+        let mut bitmap = [0u32; 4];
+        for address in addresses.into_iter().filter(|&address| address < 128) {
+            bitmap[address as usize / 32] |= 1 << (address % 32);
+        }
+        StartFiltering {
+            addresses: bitmap,
+            filter_all: BOOLEAN(0),
+        }
+    }
+}
+
 impl IoctlMessage for StartFiltering {
     const ID: u32 = ctl_code(
         FILE_DEVICE_UNKNOWN,
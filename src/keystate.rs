@@ -0,0 +1,164 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::device::Interface;
+
+/// Usage sent in every slot when too many keys are held to report them.
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+/// The keys pressed and released between two reports of an interface.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct KeyChanges {
+    pub pressed: Vec<u8>,
+    pub released: Vec<u8>,
+}
+
+#[derive(Default)]
+struct HeldKeys {
+    keys: HashSet<u8>,
+    /// Keys already released by `release_all`, whose actual release must not be reported.
+    stale: HashSet<u8>,
+}
+
+/// The keys held on each interface, turning the reports into key presses and releases. Each
+/// interface has its own state so that reports from several keyboards don't release each
+/// other's keys.
+#[derive(Default)]
+pub struct KeyState {
+    interfaces: HashMap<Interface, HeldKeys>,
+}
+
+impl KeyState {
+    /// Records the keys of a report, returning the changes since the previous report of the
+    /// same interface.
+    pub fn update(&mut self, interface: Interface, keys: &[u8]) -> KeyChanges {
+        // a roll over report doesn't say which keys are held, keep the previous state
+        if keys.contains(&ERROR_ROLL_OVER) {
+            return KeyChanges::default();
+        }
+        let keys: HashSet<u8> = keys.iter().copied().filter(|&key| key != 0).collect();
+        let held = self.interfaces.entry(interface).or_default();
+        let mut pressed: Vec<u8> = keys.difference(&held.keys).copied().collect();
+        let mut released: Vec<u8> = held
+            .keys
+            .difference(&keys)
+            .copied()
+            .filter(|key| !held.stale.remove(key))
+            .collect();
+        pressed.sort_unstable();
+        released.sort_unstable();
+        held.keys = keys;
+        if held.keys.is_empty() {
+            self.interfaces.remove(&interface);
+        }
+        KeyChanges { pressed, released }
+    }
+
+    /// The keys currently held, marking them as released so that their actual release isn't
+    /// reported by `update`.
+    pub fn release_all(&mut self) -> Vec<(Interface, u8)> {
+        let mut keys = Vec::new();
+        for (interface, held) in &mut self.interfaces {
+            for &key in held.keys.difference(&held.stale) {
+                keys.push((*interface, key));
+            }
+            held.stale = held.keys.clone();
+        }
+        keys.sort_unstable();
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeyChanges, KeyState, ERROR_ROLL_OVER};
+    use crate::device::{DeviceAddress, Interface};
+
+    fn interface(address: u16) -> Interface {
+        Interface {
+            device: DeviceAddress { bus: 1, address },
+            endpoint: 1,
+        }
+    }
+
+    fn changes(pressed: &[u8], released: &[u8]) -> KeyChanges {
+        KeyChanges {
+            pressed: pressed.to_vec(),
+            released: released.to_vec(),
+        }
+    }
+
+    #[test]
+    fn reports_presses_and_releases() {
+        let mut state = KeyState::default();
+        let keyboard = interface(1);
+        assert_eq!(
+            state.update(keyboard, &[0x74, 0, 0, 0, 0, 0]),
+            changes(&[0x74], &[])
+        );
+        assert_eq!(
+            state.update(keyboard, &[0x74, 0x75, 0, 0, 0, 0]),
+            changes(&[0x75], &[])
+        );
+        // the order of the keys in the report doesn't matter
+        assert_eq!(
+            state.update(keyboard, &[0x75, 0x74, 0, 0, 0, 0]),
+            changes(&[], &[])
+        );
+        assert_eq!(
+            state.update(keyboard, &[0x76, 0, 0, 0, 0, 0]),
+            changes(&[0x76], &[0x74, 0x75])
+        );
+        assert_eq!(state.update(keyboard, &[0; 6]), changes(&[], &[0x76]));
+    }
+
+    #[test]
+    fn interfaces_are_independent() {
+        let mut state = KeyState::default();
+        let (first, second) = (interface(1), interface(2));
+        state.update(first, &[0x74, 0, 0, 0, 0, 0]);
+        // a report of the other keyboard doesn't release the keys of the first one
+        assert_eq!(
+            state.update(second, &[0x75, 0, 0, 0, 0, 0]),
+            changes(&[0x75], &[])
+        );
+        assert_eq!(state.update(second, &[0; 6]), changes(&[], &[0x75]));
+        assert_eq!(state.update(first, &[0; 6]), changes(&[], &[0x74]));
+    }
+
+    #[test]
+    fn roll_over_keeps_the_previous_state() {
+        let mut state = KeyState::default();
+        let keyboard = interface(1);
+        state.update(keyboard, &[0x74, 0x75, 0, 0, 0, 0]);
+        assert_eq!(
+            state.update(keyboard, &[ERROR_ROLL_OVER; 6]),
+            changes(&[], &[])
+        );
+        assert_eq!(
+            state.update(keyboard, &[0x74, 0, 0, 0, 0, 0]),
+            changes(&[], &[0x75])
+        );
+    }
+
+    #[test]
+    fn release_all_suppresses_the_actual_release() {
+        let mut state = KeyState::default();
+        let (first, second) = (interface(1), interface(2));
+        state.update(first, &[0x74, 0, 0, 0, 0, 0]);
+        state.update(second, &[0x75, 0, 0, 0, 0, 0]);
+        assert_eq!(state.release_all(), vec![(first, 0x74), (second, 0x75)]);
+        // the keys are only released once
+        assert_eq!(state.release_all(), vec![]);
+        assert_eq!(
+            state.update(first, &[0x74, 0x76, 0, 0, 0, 0]),
+            changes(&[0x76], &[])
+        );
+        assert_eq!(state.update(first, &[0; 6]), changes(&[], &[0x76]));
+        assert_eq!(state.update(second, &[0; 6]), changes(&[], &[]));
+        // the keys are reported again once pressed again
+        assert_eq!(
+            state.update(second, &[0x75, 0, 0, 0, 0, 0]),
+            changes(&[0x75], &[])
+        );
+    }
+}
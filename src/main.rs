@@ -1,56 +1,49 @@
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
-use std::thread;
+use std::env;
 
 use anyhow::{bail, Context};
-use bitflags::bitflags;
-use config_file::FromConfigFile;
-use pcap_parser::traits::PcapReaderIterator;
-use pcap_parser::{LegacyPcapReader, PcapBlockOwned, PcapError};
-use pnet_packet::usbpcap::{UsbPcapFunction, UsbPcapPacket};
-use pnet_packet::Packet;
-use serde::{Deserialize, Deserializer};
+use pcap_parser::PcapError;
 use thiserror::Error;
 use windows::core::Error as WinError;
 
-use crate::driver::UsbPcapDriver;
+use crate::capture::{Capture, CaptureEvent};
+use crate::check::Severity;
+use crate::cli::{Args, Command};
+use crate::config::{CaptureFilter, Config, Driver, DriverMode};
+use crate::device::{DeviceAddress, DeviceIds};
 use crate::ioctl::{IoctlMessage, SetupBuffer, StartFiltering};
-use crate::mappings::{Mapping, MappingAction};
+use crate::keystate::KeyState;
+use crate::mappings::MappingFailures;
+use crate::process::ProcessSupervisor;
+use crate::usbhub::Win32Backend;
 use crate::vk::KeyInputManager;
 
+mod breaker;
+mod capture;
+mod check;
+mod cli;
+mod config;
+mod device;
 mod driver;
+mod hid;
 mod ioctl;
+mod keystate;
 mod mappings;
+mod mouse;
+mod notify;
+mod output;
+mod presets;
+mod process;
+mod reload;
+mod report;
+mod scheduler;
+mod shutdown;
+mod topology;
+mod usbhub;
 mod vk;
 
 const DEFAULT_INTERNAL_KERNEL_BUFFER_SIZE: usize = 4096;
 
-const DLT_USBPCAP: i32 = 249;
-const URB_FUNCTION_BULK_OR_INTERRUPT_TRANSFER: UsbPcapFunction = UsbPcapFunction(0x0009);
-const URB_INTERRUPT: u8 = 0x01;
-const DIRECTION_IN: u8 = 1;
-
-bitflags! {
-    struct Modifiers: u8 {
-        const L_CTRL = 0b0000_0001;
-        const L_SHIFT = 0b0000_0010;
-        const L_ALT = 0b0000_0100;
-        const L_WIN = 0b0000_1000;
-        const R_CTRL = 0b0001_0000;
-        const R_SHIFT = 0b0010_0000;
-        const R_ALT = 0b0100_0000;
-        const R_WIN = 0b1000_0000;
-    }
-}
-
-#[repr(C, packed)]
-#[derive(Debug)]
-struct KeyboardReport {
-    modifiers: Modifiers,
-    reserved: u8,
-    keys: [u8; 6],
-}
-
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Internal error: {0}")]
@@ -62,108 +55,266 @@ pub enum Error {
     #[error("I/O error: {0}")]
     Io(std::io::Error),
     #[error("Config error: {0}")]
-    Config(config_file::ConfigFileError),
+    Config(Box<figment::Error>),
+    #[error("Config file not found: {0}")]
+    ConfigFile(String),
+    #[error("Included preset or file not found: {0}")]
+    Include(String),
+    #[error("Include cycle: {0}")]
+    IncludeCycle(String),
 }
 
-#[derive(Deserialize)]
-pub struct Config {
-    general: GeneralConfig,
-    mappings: MappingConfig,
+fn load_config(args: &Args) -> anyhow::Result<Config> {
+    let path = args.config_path()?;
+    let config = Config::load(&path, args.driver)
+        .with_context(|| format!("Failed to load config {}", path.display()))?;
+    println!(
+        "Loaded {} mappings from {}",
+        config.mapping_count(),
+        path.display()
+    );
+    Ok(config)
 }
 
-#[derive(Deserialize)]
-pub struct GeneralConfig {
-    driver: u8,
+/// The drivers given in the config, every driver with `all`, or with `auto` the one of the hub
+/// where a device of the config is plugged.
+fn drivers(config: &Config) -> anyhow::Result<Vec<u8>> {
+    let hubs = || topology::discover(&Win32Backend).context("Failed to list the hubs");
+    let mut drivers = match &config.general.driver {
+        Driver::Number(driver) => vec![*driver],
+        Driver::List(drivers) => drivers.clone(),
+        Driver::Mode(DriverMode::All) => hubs()?.iter().map(|hub| hub.driver).collect(),
+        Driver::Mode(DriverMode::Auto) => {
+            let hubs = hubs()?;
+            let found = hubs.iter().find(|hub| {
+                hub.contains(|ids| {
+                    config.devices.values().any(|d| {
+                        d.bus.is_none_or(|bus| bus == u16::from(hub.driver)) && d.matches_ids(ids)
+                    })
+                })
+            });
+            match found {
+                Some(hub) => {
+                    println!(
+                        "Using USBPcap{}, where a configured device is plugged",
+                        hub.driver
+                    );
+                    vec![hub.driver]
+                }
+                None => {
+                    println!("No configured device found, using USBPcap1");
+                    vec![1]
+                }
+            }
+        }
+    };
+    // a hub listed twice would be read by two threads
+    let mut seen = HashSet::new();
+    drivers.retain(|driver| seen.insert(*driver));
+    if drivers.is_empty() {
+        bail!("No USBPcap hub to capture from");
+    }
+    Ok(drivers)
 }
 
-pub struct MappingConfig(HashMap<u8, Mapping>);
-
-impl<'de> Deserialize<'de> for MappingConfig {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        HashMap::<String, MappingAction>::deserialize(deserializer)?
-            .into_iter()
-            .map(|(k, v)| k.parse()
-                .map(|k| (k, (Mapping(k, v))))
-                .map_err(serde::de::Error::custom))
-            .collect::<Result<_, D::Error>>()
-            .map(MappingConfig)
+/// Releases the keys held on every device through the mappings of `config`, marking them so
+/// that their actual release is ignored, and then whatever the mappings still hold.
+fn release_held_keys(
+    keys: &mut KeyState,
+    device_ids: &HashMap<DeviceAddress, Option<DeviceIds>>,
+    config: &Config,
+    km: &KeyInputManager,
+    failures: &mut MappingFailures,
+) {
+    for (interface, key) in keys.release_all() {
+        let ids = device_ids.get(&interface.device).copied().flatten();
+        if let Some(mapping) = config.mapping(interface.device, ids, key) {
+            if let Err(err) = mapping.up(interface.device, km) {
+                failures.failed(interface.device, key, err);
+            }
+        }
     }
+    // anything the mappings didn't release, e.g. after a failed release
+    output::release_all();
 }
 
-fn main() -> anyhow::Result<()> {
-    let config = Config::from_config_file("usbrawmap.toml").context("Failed to load config")?;
-
-    println!("Loaded {} mappings", config.mappings.0.len());
+fn run(args: &Args) -> anyhow::Result<()> {
+    let mut config = load_config(args)?;
+    let _release = shutdown::install();
+    let hubs: Vec<_> = drivers(&config)?
+        .into_iter()
+        .map(|driver| (driver, config.capture_filter(driver)))
+        .collect();
+    let mut capture = Capture::start(&hubs);
+    capture.watch_config(&args.config_path()?, args.driver);
 
-    let thread = thread::spawn(move || -> Result<(), anyhow::Error> {
-        let driver = UsbPcapDriver::new(Path::new(&format!(
-            r"\\.\USBPcap{}",
-            config.general.driver
-        )))
-        .context("Failed to open driver")?;
-        let mut reader = LegacyPcapReader::new(DEFAULT_INTERNAL_KERNEL_BUFFER_SIZE, driver)
-            .context("Failed to create reader")?;
-        let mut old_keys = HashSet::new();
-        let km = KeyInputManager::new();
-        loop {
-            match reader.next() {
-                Ok((offset, block)) => {
-                    match block {
-                        PcapBlockOwned::LegacyHeader(hdr) => {
-                            if hdr.network.0 == DLT_USBPCAP {
-                                println!("USBPcap header found");
-                            } else {
-                                bail!(Error::Message("Not USBPcap"));
-                            }
-                        }
-                        PcapBlockOwned::Legacy(b) => {
-                            let packet = UsbPcapPacket::new(b.data)
-                                .context("Failed to decode USBPcap packet")?;
-                            if packet.get_transfer() == URB_INTERRUPT
-                                && packet.get_endpoint() == 1
-                                && packet.get_direction() == DIRECTION_IN
-                                && packet.get_data_length() == 8
-                                && packet.get_function() == URB_FUNCTION_BULK_OR_INTERRUPT_TRANSFER
-                            {
-                                let report: &KeyboardReport = unsafe {
-                                    &*(packet.payload().as_ptr() as *const KeyboardReport)
-                                };
-                                let new_keys = HashSet::from(report.keys);
-                                let pressed = &new_keys - &old_keys;
-                                let released = &old_keys - &new_keys;
-                                for key in pressed {
-                                    if let Some(mapping) = config.mappings.0.get(&key) {
-                                        mapping.down(&km)?;
-                                    }
-                                }
-                                for key in released {
-                                    if let Some(mapping) = config.mappings.0.get(&key) {
-                                        mapping.up(&km)?;
-                                    }
-                                }
-                                old_keys = new_keys;
-                            }
-                        }
-                        PcapBlockOwned::NG(_) => unreachable!(),
-                    }
-                    reader.consume(offset);
+    let mut keys = KeyState::default();
+    let mut km = KeyInputManager::new(config.general.repeat, config.general.injection);
+    let mut processes = ProcessSupervisor::new(config.general.max_processes);
+    let mut failures = MappingFailures::new(config.general.notify_failures);
+    // the IDs of the devices with held keys, to release them through the previous config
+    let mut device_ids = HashMap::new();
+    while let Some(event) = capture.next_event() {
+        let (interface, ids, report) = match event {
+            CaptureEvent::Report(interface, ids, report) => (interface, ids, report),
+            CaptureEvent::Disconnected(driver, err) => {
+                println!(
+                    "Capture from USBPcap{} failed: {:#}, releasing the held keys and reconnecting",
+                    driver, err
+                );
+                release_held_keys(&mut keys, &device_ids, &config, &km, &mut failures);
+                continue;
+            }
+            CaptureEvent::Failed(_, err) => {
+                if capture.all_failed() {
+                    return Err(err.context("No USBPcap hub could be opened"));
                 }
-                Err(PcapError::Eof | PcapError::Incomplete) => {
-                    reader.refill().unwrap();
+                println!("{:#}, retrying", err);
+                continue;
+            }
+            CaptureEvent::Reload(new_config) => {
+                // the keys held during a reload are released through the previous mappings
+                release_held_keys(&mut keys, &device_ids, &config, &km, &mut failures);
+                if new_config.general.driver != config.general.driver {
+                    println!("Changing the driver requires a restart");
+                }
+                if hubs
+                    .iter()
+                    .any(|(driver, filter)| new_config.capture_filter(*driver) != *filter)
+                {
+                    println!("Changing the captured devices requires a restart");
                 }
-                Err(e) => {
-                    bail!(Error::Pcap(e.to_owned_vec()));
+                km = KeyInputManager::new(new_config.general.repeat, new_config.general.injection);
+                processes.set_max_processes(new_config.general.max_processes);
+                // the new config may fix the mappings that were failing
+                failures = MappingFailures::new(new_config.general.notify_failures);
+                config = new_config;
+                println!("Loaded {} mappings", config.mapping_count());
+                continue;
+            }
+        };
+        device_ids.insert(interface.device, ids);
+        let changes = keys.update(interface, &report);
+        for key in changes.pressed {
+            if let Some(mapping) = config.mapping(interface.device, ids, key) {
+                if failures.allows(interface.device, key) {
+                    failures.report(
+                        interface.device,
+                        key,
+                        mapping.down(interface.device, &km, &processes),
+                    );
                 }
             }
         }
-    });
+        for key in changes.released {
+            if let Some(mapping) = config.mapping(interface.device, ids, key) {
+                // releasing doesn't count as a success, the mapping may have been disabled
+                if let Err(err) = mapping.up(interface.device, &km) {
+                    failures.failed(interface.device, key, err);
+                }
+            }
+        }
+        processes.reap();
+    }
+    println!("Stopped");
+    Ok(())
+}
+
+fn learn(args: &Args) -> anyhow::Result<()> {
+    let drivers = match args.driver {
+        Some(driver) => vec![driver],
+        None => drivers(&load_config(args)?)?,
+    };
+    println!("Press the keys to identify, Ctrl+C to exit");
+    let mut keys = KeyState::default();
+    let _release = shutdown::install();
+    let hubs: Vec<_> = drivers
+        .into_iter()
+        .map(|driver| (driver, CaptureFilter::All))
+        .collect();
+    let mut capture = Capture::start(&hubs);
+    while let Some(event) = capture.next_event() {
+        let (interface, ids, report) = match event {
+            CaptureEvent::Report(interface, ids, report) => (interface, ids, report),
+            CaptureEvent::Disconnected(driver, err) => {
+                println!(
+                    "Capture from USBPcap{} failed: {:#}, reconnecting",
+                    driver, err
+                );
+                continue;
+            }
+            CaptureEvent::Failed(_, err) => {
+                if capture.all_failed() {
+                    return Err(err.context("No USBPcap hub could be opened"));
+                }
+                println!("{:#}, retrying", err);
+                continue;
+            }
+            // the config isn't watched while learning
+            CaptureEvent::Reload(_) => continue,
+        };
+        let device = match ids {
+            Some(ids) => format!("{}, {}", interface.device, ids),
+            None => interface.device.to_string(),
+        };
+        for key in keys.update(interface, &report).pressed {
+            match hid::usage_name(key) {
+                Some(name) => println!("{} (0x{:02X}, {}) on {}", key, key, name, device),
+                None => println!("{} (0x{:02X}) on {}", key, key, device),
+            }
+        }
+    }
+    println!("Stopped");
+    Ok(())
+}
 
-    match thread.join() {
-        Ok(Ok(_)) => unreachable!(),
-        Ok(Err(err)) => Err(err),
-        Err(_) => bail!("Thread panicked"),
+fn check(args: &Args) -> anyhow::Result<()> {
+    let path = args.config_path()?;
+    let problems = check::check(&path, args.driver);
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    let errors = problems
+        .iter()
+        .filter(|problem| problem.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        bail!(
+            "{} error(s), {} warning(s) in {}",
+            errors,
+            problems.len() - errors,
+            path.display()
+        );
+    }
+    println!(
+        "No errors, {} warning(s) in {}",
+        problems.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+fn list_devices() -> anyhow::Result<()> {
+    let hubs = topology::discover(&Win32Backend).context("Failed to list the hubs")?;
+    if hubs.is_empty() {
+        bail!("No USBPcap hub found, is USBPcap installed?");
+    }
+    for hub in hubs {
+        print!("{}", hub);
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse(env::args().skip(1))?;
+    if args.help {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
+    match args.command {
+        Command::Run => run(&args),
+        Command::Learn => learn(&args),
+        Command::Check => check(&args),
+        Command::ListDevices => list_devices(),
     }
 }
@@ -1,42 +1,159 @@
-use std::process::Command;
+use std::time::{Duration, Instant};
 
 use serde::Deserialize;
 
-use crate::vk::{KeyInputManager, VKSeq};
+use crate::breaker::CircuitBreaker;
+use crate::device::DeviceAddress;
+use crate::mouse::MouseAction;
+use crate::notify;
+use crate::process::{ProcessSupervisor, SpawnOutcome};
+use crate::vk::{Injection, KeyInputManager, KeySequence, Repeat};
 use crate::Error;
 
 #[derive(Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
 pub enum MappingAction {
-    Keys { keys: VKSeq },
-    Program { path: String },
+    Keys {
+        keys: KeySequence,
+        #[serde(default)]
+        repeat: Option<Repeat>,
+        #[serde(default)]
+        injection: Option<Injection>,
+    },
+    Program {
+        path: String,
+        #[serde(default)]
+        single_instance: bool,
+    },
+    Mouse {
+        #[serde(flatten)]
+        action: MouseAction,
+        #[serde(default)]
+        repeat: Option<Repeat>,
+    },
 }
 
 pub struct Mapping(pub u8, pub MappingAction);
 
+/// A scancode on a device, what a mapping is held for since the same scancode can be mapped
+/// on several keyboards.
+pub type MappingKey = (DeviceAddress, u8);
+
 impl Mapping {
-    pub fn down(&self, key_manager: &KeyInputManager) -> Result<(), Error> {
+    /// Runs the mapping for its key pressed on `device`.
+    pub fn down(
+        &self,
+        device: DeviceAddress,
+        key_manager: &KeyInputManager,
+        processes: &ProcessSupervisor,
+    ) -> Result<(), Error> {
+        let id = (device, self.0);
         match &self.1 {
-            MappingAction::Keys { keys } => {
-                key_manager.down(self.0, keys).map_err(Error::Windows)?;
+            MappingAction::Keys {
+                keys,
+                repeat,
+                injection,
+            } => {
+                key_manager
+                    .down(id, keys, *repeat, *injection)
+                    .map_err(Error::Windows)?;
             }
-            MappingAction::Program { path } => {
-                Command::new(path).spawn().map_err(Error::Io)?;
+            MappingAction::Program {
+                path,
+                single_instance,
+            } => match processes
+                .spawn(id, path, *single_instance)
+                .map_err(Error::Io)?
+            {
+                SpawnOutcome::Spawned | SpawnOutcome::Focused => {}
+                SpawnOutcome::AlreadyRunning => {
+                    println!("{} is already running, skipping", path);
+                }
+                SpawnOutcome::LimitReached => {
+                    println!(
+                        "Not starting {}: {} processes already running",
+                        path,
+                        processes.running()
+                    );
+                }
+            },
+            MappingAction::Mouse { action, repeat } => {
+                key_manager
+                    .mouse_down(id, *action, *repeat)
+                    .map_err(Error::Windows)?;
             }
         }
         Ok(())
     }
 
-    pub fn up(&self, key_manager: &KeyInputManager) -> Result<(), Error> {
+    pub fn up(&self, device: DeviceAddress, key_manager: &KeyInputManager) -> Result<(), Error> {
+        let id = (device, self.0);
         match &self.1 {
-            MappingAction::Keys { keys } => {
-                key_manager.up(self.0, keys).map_err(Error::Windows)?;
+            MappingAction::Keys {
+                keys, injection, ..
+            } => {
+                key_manager
+                    .up(id, keys, *injection)
+                    .map_err(Error::Windows)?;
             }
             MappingAction::Program { .. } => {
                 //
             }
+            MappingAction::Mouse { action, .. } => {
+                key_manager.mouse_up(id, *action).map_err(Error::Windows)?;
+            }
         }
         Ok(())
     }
 }
+
+/// Consecutive failures after which a mapping is disabled.
+const FAILURE_THRESHOLD: u32 = 3;
+const DISABLED_FOR: Duration = Duration::from_secs(60);
+
+/// Logs the failures of the mappings instead of stopping, and disables for a while the ones
+/// that keep failing.
+pub struct MappingFailures {
+    breaker: CircuitBreaker<MappingKey>,
+    notify: bool,
+}
+
+impl MappingFailures {
+    /// `notify` also shows the failures in a message box.
+    pub fn new(notify: bool) -> Self {
+        MappingFailures {
+            breaker: CircuitBreaker::new(FAILURE_THRESHOLD, DISABLED_FOR),
+            notify,
+        }
+    }
+
+    /// Whether the mapping of `key` on `device` can run.
+    pub fn allows(&mut self, device: DeviceAddress, key: u8) -> bool {
+        self.breaker.allows(&(device, key), Instant::now())
+    }
+
+    /// Records the outcome of pressing the mapping of `key` on `device`.
+    pub fn report(&mut self, device: DeviceAddress, key: u8, result: Result<(), Error>) {
+        match result {
+            Ok(()) => self.breaker.success(&(device, key)),
+            Err(err) => self.failed(device, key, err),
+        }
+    }
+
+    /// Logs the failure of the mapping of `key` on `device`, disabling it if it keeps failing.
+    pub fn failed(&mut self, device: DeviceAddress, key: u8, err: Error) {
+        let mut message = format!("Mapping of key {} on {} failed: {}", key, device, err);
+        if self.breaker.failure((device, key), Instant::now()) {
+            message += &format!(
+                "\nIt failed {} times in a row and is disabled for {} seconds",
+                FAILURE_THRESHOLD,
+                DISABLED_FOR.as_secs()
+            );
+        }
+        println!("{}", message);
+        if self.notify {
+            notify::show(message);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use serde::Deserialize;
+use windows::Win32::UI::Input::KeyboardAndMouse::{
+    INPUT, INPUT_0, INPUT_MOUSE, MOUSEEVENTF_HWHEEL, MOUSEEVENTF_LEFTDOWN, MOUSEEVENTF_LEFTUP,
+    MOUSEEVENTF_MIDDLEDOWN, MOUSEEVENTF_MIDDLEUP, MOUSEEVENTF_MOVE, MOUSEEVENTF_RIGHTDOWN,
+    MOUSEEVENTF_RIGHTUP, MOUSEEVENTF_WHEEL, MOUSEEVENTF_XDOWN, MOUSEEVENTF_XUP, MOUSEINPUT,
+    MOUSE_EVENT_FLAGS,
+};
+use windows::Win32::UI::WindowsAndMessaging::{WHEEL_DELTA, XBUTTON1, XBUTTON2};
+
+use crate::output::InputEvent;
+use crate::vk::KeyAction;
+
+#[derive(Copy, Clone, Hash, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    X1,
+    X2,
+}
+
+#[derive(Copy, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MouseAction {
+    /// Held as long as the key is held.
+    Button(MouseButton),
+    /// Wheel steps, positive scrolls up. Repeats while the key is held.
+    Wheel(i32),
+    /// Horizontal wheel steps, positive scrolls right. Repeats while the key is held.
+    Hwheel(i32),
+    /// Relative pointer movement. Repeats while the key is held.
+    Move(i32, i32),
+}
+
+impl MouseButton {
+    fn event(&self, action: KeyAction) -> (MOUSE_EVENT_FLAGS, i32) {
+        match (self, action) {
+            (MouseButton::Left, KeyAction::Down) => (MOUSEEVENTF_LEFTDOWN, 0),
+            (MouseButton::Left, KeyAction::Up) => (MOUSEEVENTF_LEFTUP, 0),
+            (MouseButton::Right, KeyAction::Down) => (MOUSEEVENTF_RIGHTDOWN, 0),
+            (MouseButton::Right, KeyAction::Up) => (MOUSEEVENTF_RIGHTUP, 0),
+            (MouseButton::Middle, KeyAction::Down) => (MOUSEEVENTF_MIDDLEDOWN, 0),
+            (MouseButton::Middle, KeyAction::Up) => (MOUSEEVENTF_MIDDLEUP, 0),
+            (MouseButton::X1, KeyAction::Down) => (MOUSEEVENTF_XDOWN, XBUTTON1.0 as i32),
+            (MouseButton::X1, KeyAction::Up) => (MOUSEEVENTF_XUP, XBUTTON1.0 as i32),
+            (MouseButton::X2, KeyAction::Down) => (MOUSEEVENTF_XDOWN, XBUTTON2.0 as i32),
+            (MouseButton::X2, KeyAction::Up) => (MOUSEEVENTF_XUP, XBUTTON2.0 as i32),
+        }
+    }
+
+    pub fn input(&self, action: KeyAction) -> INPUT {
+        let (flags, data) = self.event(action);
+        input(flags, 0, 0, data)
+    }
+}
+
+impl MouseAction {
+    /// The event to send when the key is pressed or released. Only buttons are released, the
+    /// other actions are sent on press.
+    pub fn event(&self, action: KeyAction) -> Option<InputEvent> {
+        match (*self, action) {
+            (MouseAction::Button(button), action) => Some(InputEvent::MouseButton(button, action)),
+            (_, KeyAction::Up) => None,
+            (MouseAction::Wheel(steps), KeyAction::Down) => Some(InputEvent::Wheel(steps)),
+            (MouseAction::Hwheel(steps), KeyAction::Down) => Some(InputEvent::Hwheel(steps)),
+            (MouseAction::Move(dx, dy), KeyAction::Down) => Some(InputEvent::Move(dx, dy)),
+        }
+    }
+}
+
+/// A rotation of `steps` notches, saturated rather than overflowing with huge configured values.
+pub fn wheel_input(steps: i32) -> INPUT {
+    input(
+        MOUSEEVENTF_WHEEL,
+        0,
+        0,
+        steps.saturating_mul(WHEEL_DELTA as i32),
+    )
+}
+
+pub fn hwheel_input(steps: i32) -> INPUT {
+    input(
+        MOUSEEVENTF_HWHEEL,
+        0,
+        0,
+        steps.saturating_mul(WHEEL_DELTA as i32),
+    )
+}
+
+pub fn move_input(dx: i32, dy: i32) -> INPUT {
+    input(MOUSEEVENTF_MOVE, dx, dy, 0)
+}
+
+fn input(flags: MOUSE_EVENT_FLAGS, dx: i32, dy: i32, data: i32) -> INPUT {
+    INPUT {
+        r#type: INPUT_MOUSE,
+        Anonymous: INPUT_0 {
+            mi: MOUSEINPUT {
+                dx,
+                dy,
+                mouseData: data,
+                dwFlags: flags,
+                time: 0,
+                dwExtraInfo: 0,
+            },
+        },
+    }
+}
@@ -0,0 +1,24 @@
+use std::thread;
+
+use widestring::WideCString;
+use windows::core::PCWSTR;
+use windows::Win32::Foundation::HWND;
+use windows::Win32::UI::WindowsAndMessaging::{
+    MessageBoxW, MB_ICONWARNING, MB_OK, MB_SETFOREGROUND,
+};
+
+/// Shows `message` in a message box, without waiting for it to be closed.
+pub fn show(message: String) {
+    thread::spawn(move || {
+        let text = WideCString::from_str_truncate(message);
+        let caption = WideCString::from_str_truncate("usbrawmap");
+        unsafe {
+            MessageBoxW(
+                HWND::default(),
+                PCWSTR(text.as_ptr()),
+                PCWSTR(caption.as_ptr()),
+                MB_OK | MB_ICONWARNING | MB_SETFOREGROUND,
+            );
+        }
+    });
+}
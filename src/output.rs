@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use lazy_static::lazy_static;
+use windows::core::Error as WinError;
+use windows::Win32::UI::Input::KeyboardAndMouse::{SendInput, INPUT};
+
+use crate::mouse::{hwheel_input, move_input, wheel_input, MouseButton};
+use crate::vk::{Injection, KeyAction, VK};
+
+#[derive(Copy, Clone)]
+pub enum InputEvent {
+    Key(VK, KeyAction, Injection),
+    MouseButton(MouseButton, KeyAction),
+    Wheel(i32),
+    Hwheel(i32),
+    Move(i32, i32),
+}
+
+pub trait Output: Send + Sync {
+    /// Injects the events as a single batch, so that no other input can come in between.
+    fn send(&self, events: &[InputEvent]) -> Result<(), WinError>;
+}
+
+pub struct SendInputOutput;
+
+impl Output for SendInputOutput {
+    fn send(&self, events: &[InputEvent]) -> Result<(), WinError> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let inputs: Vec<INPUT> = events
+            .iter()
+            .map(|event| match *event {
+                InputEvent::Key(vk, action, injection) => vk.input(action, injection),
+                InputEvent::MouseButton(button, action) => button.input(action),
+                InputEvent::Wheel(steps) => wheel_input(steps),
+                InputEvent::Hwheel(steps) => hwheel_input(steps),
+                InputEvent::Move(dx, dy) => move_input(dx, dy),
+            })
+            .collect();
+        let sent = unsafe { SendInput(&inputs, std::mem::size_of::<INPUT>() as i32) };
+        if sent as usize != inputs.len() {
+            Err(WinError::from_win32())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A key or button that stays down until it is released, however it was injected.
+#[derive(Copy, Clone, Hash, PartialEq, Eq)]
+enum HeldInput {
+    Key(u8),
+    MouseButton(MouseButton),
+}
+
+impl HeldInput {
+    fn of(event: &InputEvent) -> Option<(HeldInput, KeyAction)> {
+        match *event {
+            InputEvent::Key(vk, action, _) => Some((HeldInput::Key(vk.code()), action)),
+            InputEvent::MouseButton(button, action) => {
+                Some((HeldInput::MouseButton(button), action))
+            }
+            _ => None,
+        }
+    }
+}
+
+struct Held {
+    count: usize,
+    /// The event releasing the input, injected the same way it was pressed.
+    release: InputEvent,
+}
+
+/// The keys and buttons pressed by the mappings, with the number of mappings holding each of
+/// them, so that a modifier shared by two mappings is only released with the last one and
+/// everything can be released when usbrawmap stops.
+#[derive(Default)]
+pub struct HeldInputs(Mutex<HashMap<HeldInput, Held>>);
+
+impl HeldInputs {
+    fn lock(&self) -> MutexGuard<'_, HashMap<HeldInput, Held>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Counts the presses and releases of `events`, leaving out the presses of inputs that are
+    /// already down and the releases of inputs that other mappings still hold.
+    pub fn track(&self, events: &[InputEvent]) -> Vec<InputEvent> {
+        let mut held = self.lock();
+        events
+            .iter()
+            .filter(|event| match HeldInput::of(event) {
+                Some((input, KeyAction::Down)) => {
+                    let entry = held.entry(input).or_insert(Held {
+                        count: 0,
+                        release: release_event(event),
+                    });
+                    entry.count += 1;
+                    entry.count == 1
+                }
+                Some((input, KeyAction::Up)) => match held.get_mut(&input) {
+                    Some(entry) if entry.count > 1 => {
+                        entry.count -= 1;
+                        false
+                    }
+                    Some(_) => {
+                        held.remove(&input);
+                        true
+                    }
+                    // released already, by `release_all`
+                    None => false,
+                },
+                None => true,
+            })
+            .copied()
+            .collect()
+    }
+
+    /// The events of a repeat that are still relevant: key presses are only repeated while
+    /// the key is held.
+    pub fn repeated(&self, events: &[InputEvent]) -> Vec<InputEvent> {
+        let held = self.lock();
+        events
+            .iter()
+            .filter(|event| match HeldInput::of(event) {
+                Some((input, _)) => held.contains_key(&input),
+                None => true,
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Releases every input still held, whichever mappings pressed it.
+    pub fn release_all(&self, output: &dyn Output) -> Result<(), WinError> {
+        let events: Vec<InputEvent> = self.lock().drain().map(|(_, held)| held.release).collect();
+        if events.is_empty() {
+            return Ok(());
+        }
+        output.send(&events)
+    }
+}
+
+fn release_event(event: &InputEvent) -> InputEvent {
+    match *event {
+        InputEvent::Key(vk, _, injection) => InputEvent::Key(vk, KeyAction::Up, injection),
+        InputEvent::MouseButton(button, _) => InputEvent::MouseButton(button, KeyAction::Up),
+        other => other,
+    }
+}
+
+lazy_static! {
+    /// The inputs held by all the mappings, global so that they can be released from the
+    /// console control handler and the panic hook.
+    pub static ref HELD: HeldInputs = HeldInputs::default();
+}
+
+/// Releases everything the mappings hold, when usbrawmap stops or fails.
+pub fn release_all() {
+    if let Err(err) = HELD.release_all(&SendInputOutput) {
+        println!("Failed to release the held keys: {}", err);
+    }
+}
@@ -0,0 +1,19 @@
+const SUN_TYPE6: &str = include_str!("../presets/sun-type6.toml");
+
+/// Built-in config files that can be included by name.
+static PRESETS: &[(&str, &str)] = &[
+    ("sun-type6", SUN_TYPE6),
+    ("sun-type7", SUN_TYPE6),
+    (
+        "apple-extended",
+        include_str!("../presets/apple-extended.toml"),
+    ),
+    ("macro-pad", include_str!("../presets/macro-pad.toml")),
+];
+
+pub fn get(name: &str) -> Option<&'static str> {
+    PRESETS
+        .iter()
+        .find(|(preset, _)| *preset == name)
+        .map(|(_, content)| *content)
+}
@@ -0,0 +1,294 @@
+use std::cell::RefCell;
+use std::io;
+use std::process::{Child, Command};
+
+use windows::Win32::Foundation::{BOOL, HWND, LPARAM};
+use windows::Win32::UI::WindowsAndMessaging::{
+    EnumWindows, GetWindowThreadProcessId, IsWindowVisible, SetForegroundWindow,
+};
+
+use crate::mappings::MappingKey;
+
+pub trait ChildProcess {
+    /// Returns `true` if the process has exited (and has been reaped).
+    fn try_wait(&mut self) -> io::Result<bool>;
+}
+
+pub trait ProcessSpawner {
+    type Child: ChildProcess;
+
+    fn spawn(&self, path: &str) -> io::Result<Self::Child>;
+
+    /// Brings a window of the process to the foreground, returns `false` if it has none.
+    fn focus(&self, child: &Self::Child) -> bool;
+}
+
+impl ChildProcess for Child {
+    fn try_wait(&mut self) -> io::Result<bool> {
+        Child::try_wait(self).map(|status| status.is_some())
+    }
+}
+
+pub struct CommandSpawner;
+
+impl ProcessSpawner for CommandSpawner {
+    type Child = Child;
+
+    fn spawn(&self, path: &str) -> io::Result<Child> {
+        Command::new(path).spawn()
+    }
+
+    fn focus(&self, child: &Child) -> bool {
+        struct Search {
+            pid: u32,
+            found: Option<HWND>,
+        }
+
+        extern "system" fn callback(hwnd: HWND, param: LPARAM) -> BOOL {
+            let search = unsafe { &mut *(param.0 as *mut Search) };
+            let mut pid = 0;
+            unsafe { GetWindowThreadProcessId(hwnd, &mut pid) };
+            if pid == search.pid && unsafe { IsWindowVisible(hwnd) }.as_bool() {
+                search.found = Some(hwnd);
+                BOOL(0)
+            } else {
+                BOOL(1)
+            }
+        }
+
+        let mut search = Search {
+            pid: child.id(),
+            found: None,
+        };
+        unsafe { EnumWindows(Some(callback), LPARAM(&mut search as *mut _ as isize)) };
+        match search.found {
+            Some(hwnd) => unsafe { SetForegroundWindow(hwnd) }.as_bool(),
+            None => false,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SpawnOutcome {
+    Spawned,
+    Focused,
+    AlreadyRunning,
+    LimitReached,
+}
+
+/// Keeps track of the processes launched by `Program` mappings so they can be reaped.
+pub struct ProcessSupervisor<S: ProcessSpawner = CommandSpawner> {
+    spawner: S,
+    max_processes: Option<usize>,
+    /// The children, with the key of the device whose mapping started them.
+    children: RefCell<Vec<(MappingKey, S::Child)>>,
+}
+
+impl ProcessSupervisor {
+    pub fn new(max_processes: Option<usize>) -> Self {
+        ProcessSupervisor::with_spawner(CommandSpawner, max_processes)
+    }
+}
+
+impl<S: ProcessSpawner> ProcessSupervisor<S> {
+    pub fn with_spawner(spawner: S, max_processes: Option<usize>) -> Self {
+        ProcessSupervisor {
+            spawner,
+            max_processes,
+            children: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn set_max_processes(&mut self, max_processes: Option<usize>) {
+        self.max_processes = max_processes;
+    }
+
+    /// Forgets about the children that have exited.
+    pub fn reap(&self) {
+        self.children
+            .borrow_mut()
+            .retain_mut(|(_, child)| !matches!(child.try_wait(), Ok(true)));
+    }
+
+    pub fn running(&self) -> usize {
+        self.children.borrow().len()
+    }
+
+    pub fn spawn(
+        &self,
+        id: MappingKey,
+        path: &str,
+        single_instance: bool,
+    ) -> io::Result<SpawnOutcome> {
+        self.reap();
+        let mut children = self.children.borrow_mut();
+        if single_instance {
+            if let Some((_, child)) = children.iter().find(|(owner, _)| *owner == id) {
+                return Ok(if self.spawner.focus(child) {
+                    SpawnOutcome::Focused
+                } else {
+                    SpawnOutcome::AlreadyRunning
+                });
+            }
+        }
+        if matches!(self.max_processes, Some(max) if children.len() >= max) {
+            return Ok(SpawnOutcome::LimitReached);
+        }
+        children.push((id, self.spawner.spawn(path)?));
+        Ok(SpawnOutcome::Spawned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::io;
+    use std::rc::Rc;
+
+    use super::{ChildProcess, ProcessSpawner, ProcessSupervisor, SpawnOutcome};
+    use crate::device::DeviceAddress;
+    use crate::mappings::MappingKey;
+
+    const KEYBOARD: DeviceAddress = DeviceAddress { bus: 1, address: 5 };
+
+    fn key(usage: u8) -> MappingKey {
+        (KEYBOARD, usage)
+    }
+
+    struct FakeChild(Rc<Cell<bool>>);
+
+    impl ChildProcess for FakeChild {
+        fn try_wait(&mut self) -> io::Result<bool> {
+            Ok(self.0.get())
+        }
+    }
+
+    /// Records the spawned processes, which exit when their flag is set.
+    #[derive(Default)]
+    struct FakeSpawner {
+        has_window: bool,
+        spawned: RefCell<Vec<Rc<Cell<bool>>>>,
+    }
+
+    impl FakeSpawner {
+        fn exit(&self, index: usize) {
+            self.spawned.borrow()[index].set(true);
+        }
+    }
+
+    impl ProcessSpawner for &FakeSpawner {
+        type Child = FakeChild;
+
+        fn spawn(&self, _path: &str) -> io::Result<FakeChild> {
+            let exited = Rc::new(Cell::new(false));
+            self.spawned.borrow_mut().push(exited.clone());
+            Ok(FakeChild(exited))
+        }
+
+        fn focus(&self, _child: &FakeChild) -> bool {
+            self.has_window
+        }
+    }
+
+    #[test]
+    fn single_instance_focuses_the_running_process() {
+        let spawner = FakeSpawner {
+            has_window: true,
+            ..FakeSpawner::default()
+        };
+        let processes = ProcessSupervisor::with_spawner(&spawner, None);
+        assert_eq!(
+            processes.spawn(key(1), "a", true).unwrap(),
+            SpawnOutcome::Spawned
+        );
+        assert_eq!(
+            processes.spawn(key(1), "a", true).unwrap(),
+            SpawnOutcome::Focused
+        );
+        assert_eq!(spawner.spawned.borrow().len(), 1);
+    }
+
+    #[test]
+    fn single_instance_skips_a_process_without_window() {
+        let spawner = FakeSpawner::default();
+        let processes = ProcessSupervisor::with_spawner(&spawner, None);
+        assert_eq!(
+            processes.spawn(key(1), "a", true).unwrap(),
+            SpawnOutcome::Spawned
+        );
+        assert_eq!(
+            processes.spawn(key(1), "a", true).unwrap(),
+            SpawnOutcome::AlreadyRunning
+        );
+        // another mapping isn't affected
+        assert_eq!(
+            processes.spawn(key(2), "a", true).unwrap(),
+            SpawnOutcome::Spawned
+        );
+        // nor the same key on another keyboard
+        let other = DeviceAddress { bus: 1, address: 6 };
+        assert_eq!(
+            processes.spawn((other, 1), "a", true).unwrap(),
+            SpawnOutcome::Spawned
+        );
+        // once it exits, it is started again
+        spawner.exit(0);
+        assert_eq!(
+            processes.spawn(key(1), "a", true).unwrap(),
+            SpawnOutcome::Spawned
+        );
+    }
+
+    #[test]
+    fn multiple_instances_are_spawned() {
+        let spawner = FakeSpawner::default();
+        let processes = ProcessSupervisor::with_spawner(&spawner, None);
+        for _ in 0..3 {
+            assert_eq!(
+                processes.spawn(key(1), "a", false).unwrap(),
+                SpawnOutcome::Spawned
+            );
+        }
+        assert_eq!(processes.running(), 3);
+    }
+
+    #[test]
+    fn max_processes_limits_the_running_processes() {
+        let spawner = FakeSpawner::default();
+        let processes = ProcessSupervisor::with_spawner(&spawner, Some(2));
+        assert_eq!(
+            processes.spawn(key(1), "a", false).unwrap(),
+            SpawnOutcome::Spawned
+        );
+        assert_eq!(
+            processes.spawn(key(2), "b", false).unwrap(),
+            SpawnOutcome::Spawned
+        );
+        assert_eq!(
+            processes.spawn(key(3), "c", false).unwrap(),
+            SpawnOutcome::LimitReached
+        );
+        spawner.exit(1);
+        assert_eq!(
+            processes.spawn(key(3), "c", false).unwrap(),
+            SpawnOutcome::Spawned
+        );
+        assert_eq!(spawner.spawned.borrow().len(), 3);
+    }
+
+    #[test]
+    fn reap_forgets_the_exited_processes() {
+        let spawner = FakeSpawner::default();
+        let processes = ProcessSupervisor::with_spawner(&spawner, None);
+        processes.spawn(key(1), "a", false).unwrap();
+        processes.spawn(key(2), "b", false).unwrap();
+        processes.reap();
+        assert_eq!(processes.running(), 2);
+        spawner.exit(0);
+        processes.reap();
+        assert_eq!(processes.running(), 1);
+        spawner.exit(1);
+        processes.reap();
+        assert_eq!(processes.running(), 0);
+    }
+}
@@ -0,0 +1,62 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::check::{self, Problem};
+use crate::config::Config;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+fn modified(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| fs::metadata(file).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Polls the config files on a thread, loading the config again when one of them changes and
+/// passing it to `on_reload`, until it returns false.
+pub fn watch<F>(path: &Path, driver: Option<u8>, mut on_reload: F)
+where
+    F: FnMut(Config) -> bool + Send + 'static,
+{
+    let path = path.to_path_buf();
+    thread::spawn(move || {
+        let mut files = Config::files(&path);
+        let mut last = modified(&files);
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let current = modified(&files);
+            if current == last {
+                continue;
+            }
+            last = current;
+            println!("Config changed, reloading");
+            // errors in the config itself prevent the reload, but like when starting, a
+            // missing program only makes its mapping fail
+            let problems = check::check(&path, driver);
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            if problems.iter().any(Problem::prevents_loading) {
+                println!("Keeping the previous config");
+                continue;
+            }
+            match Config::load(&path, driver) {
+                Ok(config) => {
+                    if !on_reload(config) {
+                        return;
+                    }
+                    // the included files may have changed
+                    files = Config::files(&path);
+                    last = modified(&files);
+                }
+                Err(err) => {
+                    println!("{}", err);
+                    println!("Keeping the previous config");
+                }
+            }
+        }
+    });
+}
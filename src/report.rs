@@ -0,0 +1,220 @@
+use bitflags::bitflags;
+use pnet_packet::usbpcap::{UsbPcapFunction, UsbPcapPacket};
+use pnet_packet::Packet;
+use thiserror::Error;
+
+/// Size of the fixed part of the USBPcap packet header.
+const USBPCAP_HEADER_SIZE: usize = 27;
+/// Size of a boot protocol keyboard report.
+const KEYBOARD_REPORT_SIZE: usize = 8;
+
+const URB_FUNCTION_BULK_OR_INTERRUPT_TRANSFER: UsbPcapFunction = UsbPcapFunction(0x0009);
+const URB_INTERRUPT: u8 = 0x01;
+const DIRECTION_IN: u8 = 1;
+
+bitflags! {
+    pub struct Modifiers: u8 {
+        const L_CTRL = 0b0000_0001;
+        const L_SHIFT = 0b0000_0010;
+        const L_ALT = 0b0000_0100;
+        const L_WIN = 0b0000_1000;
+        const R_CTRL = 0b0001_0000;
+        const R_SHIFT = 0b0010_0000;
+        const R_ALT = 0b0100_0000;
+        const R_WIN = 0b1000_0000;
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("Invalid USBPcap header length: {0}")]
+    HeaderLength(u16),
+    #[error("Truncated packet: {actual} bytes of data instead of {expected}")]
+    Truncated { expected: usize, actual: usize },
+    #[error("Invalid keyboard report length: {0}")]
+    ReportLength(usize),
+}
+
+/// A boot protocol keyboard report.
+#[derive(Debug, PartialEq, Eq)]
+pub struct KeyboardReport {
+    pub modifiers: Modifiers,
+    /// The usages of the keys held, 0 in the unused slots.
+    pub keys: [u8; 6],
+}
+
+impl KeyboardReport {
+    pub fn parse(data: &[u8]) -> Result<KeyboardReport, DecodeError> {
+        if data.len() != KEYBOARD_REPORT_SIZE {
+            return Err(DecodeError::ReportLength(data.len()));
+        }
+        let mut keys = [0; 6];
+        keys.copy_from_slice(&data[2..]);
+        Ok(KeyboardReport {
+            modifiers: Modifiers::from_bits_truncate(data[0]),
+            keys,
+        })
+    }
+}
+
+/// The data of a packet, checked against the lengths in its header, which the capture doesn't
+/// guarantee.
+pub fn payload<'p>(packet: &'p UsbPcapPacket) -> Result<&'p [u8], DecodeError> {
+    let data = packet.packet();
+    let header_length = packet.get_header_length();
+    if (header_length as usize) < USBPCAP_HEADER_SIZE || header_length as usize > data.len() {
+        return Err(DecodeError::HeaderLength(header_length));
+    }
+    let payload = &data[header_length as usize..];
+    let expected = packet.get_data_length() as usize;
+    if payload.len() < expected {
+        return Err(DecodeError::Truncated {
+            expected,
+            actual: payload.len(),
+        });
+    }
+    Ok(&payload[..expected])
+}
+
+/// The keyboard report carried by the packet, if it is an interrupt transfer from a keyboard.
+pub fn keyboard_report(packet: &UsbPcapPacket) -> Result<Option<KeyboardReport>, DecodeError> {
+    if packet.get_transfer() != URB_INTERRUPT
+        || packet.get_endpoint() != 1
+        || packet.get_direction() != DIRECTION_IN
+        || packet.get_function() != URB_FUNCTION_BULK_OR_INTERRUPT_TRANSFER
+    {
+        return Ok(None);
+    }
+    KeyboardReport::parse(payload(packet)?).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use pnet_packet::usbpcap::{MutableUsbPcapPacket, UsbPcapPacket};
+
+    use super::*;
+
+    /// A keyboard report from endpoint 1, with the given lengths in its header.
+    fn packet(header_length: u16, data_length: u32, data: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0; USBPCAP_HEADER_SIZE];
+        {
+            let mut packet = MutableUsbPcapPacket::new(&mut buffer).unwrap();
+            packet.set_header_length(header_length);
+            packet.set_function(URB_FUNCTION_BULK_OR_INTERRUPT_TRANSFER);
+            packet.set_bus(1);
+            packet.set_device(5);
+            packet.set_direction(DIRECTION_IN);
+            packet.set_endpoint(1);
+            packet.set_transfer(URB_INTERRUPT);
+            packet.set_data_length(data_length);
+        }
+        buffer.extend_from_slice(data);
+        buffer
+    }
+
+    const REPORT: [u8; 8] = [0b0010_0001, 0, 0x74, 0x75, 0, 0, 0, 0];
+
+    #[test]
+    fn parses_a_report() {
+        assert_eq!(
+            KeyboardReport::parse(&REPORT),
+            Ok(KeyboardReport {
+                modifiers: Modifiers::L_CTRL | Modifiers::R_SHIFT,
+                keys: [0x74, 0x75, 0, 0, 0, 0],
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_report_lengths() {
+        assert_eq!(
+            KeyboardReport::parse(&[]),
+            Err(DecodeError::ReportLength(0))
+        );
+        assert_eq!(
+            KeyboardReport::parse(&REPORT[..7]),
+            Err(DecodeError::ReportLength(7))
+        );
+        assert_eq!(
+            KeyboardReport::parse(&[0; 9]),
+            Err(DecodeError::ReportLength(9))
+        );
+    }
+
+    #[test]
+    fn checks_the_header_length() {
+        let data = packet(26, 8, &REPORT);
+        let packet_26 = UsbPcapPacket::new(&data).unwrap();
+        assert_eq!(payload(&packet_26), Err(DecodeError::HeaderLength(26)));
+        // past the end of the captured bytes
+        let data = packet(40, 8, &REPORT);
+        let packet_40 = UsbPcapPacket::new(&data).unwrap();
+        assert_eq!(payload(&packet_40), Err(DecodeError::HeaderLength(40)));
+        assert_eq!(
+            keyboard_report(&packet_40),
+            Err(DecodeError::HeaderLength(40))
+        );
+    }
+
+    #[test]
+    fn checks_the_data_length() {
+        let data = packet(27, 8, &REPORT[..4]);
+        let truncated = UsbPcapPacket::new(&data).unwrap();
+        assert_eq!(
+            payload(&truncated),
+            Err(DecodeError::Truncated {
+                expected: 8,
+                actual: 4
+            })
+        );
+        // the bytes after the data aren't part of it
+        let data = packet(27, 4, &REPORT);
+        assert_eq!(
+            payload(&UsbPcapPacket::new(&data).unwrap()),
+            Ok(&REPORT[..4])
+        );
+    }
+
+    #[test]
+    fn decodes_keyboard_reports() {
+        let data = packet(27, 8, &REPORT);
+        let report = keyboard_report(&UsbPcapPacket::new(&data).unwrap()).unwrap();
+        assert_eq!(
+            report.map(|report| report.keys),
+            Some([0x74, 0x75, 0, 0, 0, 0])
+        );
+        // a header with extra fields
+        let mut data = packet(29, 8, &[0, 0]);
+        data.extend_from_slice(&REPORT);
+        let report = keyboard_report(&UsbPcapPacket::new(&data).unwrap()).unwrap();
+        assert_eq!(
+            report.map(|report| report.keys),
+            Some([0x74, 0x75, 0, 0, 0, 0])
+        );
+        let data = packet(27, 16, &[0; 16]);
+        assert_eq!(
+            keyboard_report(&UsbPcapPacket::new(&data).unwrap()),
+            Err(DecodeError::ReportLength(16))
+        );
+    }
+
+    #[test]
+    fn skips_other_transfers() {
+        let mut data = packet(27, 8, &REPORT);
+        MutableUsbPcapPacket::new(&mut data)
+            .unwrap()
+            .set_endpoint(2);
+        assert_eq!(
+            keyboard_report(&UsbPcapPacket::new(&data).unwrap()),
+            Ok(None)
+        );
+        let mut data = packet(27, 8, &REPORT);
+        MutableUsbPcapPacket::new(&mut data)
+            .unwrap()
+            .set_direction(0);
+        assert_eq!(
+            keyboard_report(&UsbPcapPacket::new(&data).unwrap()),
+            Ok(None)
+        );
+    }
+}
@@ -0,0 +1,253 @@
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use windows::core::Error as WinError;
+
+use crate::mappings::MappingKey;
+
+pub type Task = Box<dyn FnMut() -> Result<(), WinError> + Send>;
+
+struct Timer<Id> {
+    id: Id,
+    next: Instant,
+    period: Duration,
+    task: Task,
+}
+
+/// The timer bookkeeping, driven by an explicit `now` so it doesn't depend on a real clock.
+pub struct Timers<Id> {
+    timers: Vec<Timer<Id>>,
+}
+
+impl<Id> Default for Timers<Id> {
+    fn default() -> Self {
+        Timers { timers: Vec::new() }
+    }
+}
+
+impl<Id: Copy + PartialEq> Timers<Id> {
+    /// Runs `task` after `delay` and then every `period`, replacing any timer with the same id.
+    pub fn insert(&mut self, id: Id, now: Instant, delay: Duration, period: Duration, task: Task) {
+        self.remove(id);
+        self.timers.push(Timer {
+            id,
+            next: now + delay,
+            period,
+            task,
+        });
+    }
+
+    pub fn remove(&mut self, id: Id) -> bool {
+        let len = self.timers.len();
+        self.timers.retain(|timer| timer.id != id);
+        self.timers.len() != len
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.iter().map(|timer| timer.next).min()
+    }
+
+    /// Runs every timer that is due at `now`. A timer whose task fails is removed.
+    ///
+    /// Timers that fell behind (e.g. the system was asleep) fire once instead of catching up.
+    pub fn run_due(&mut self, now: Instant) -> Vec<(Id, WinError)> {
+        let mut errors = Vec::new();
+        self.timers.retain_mut(|timer| {
+            if timer.next > now {
+                return true;
+            }
+            if let Err(err) = (timer.task)() {
+                errors.push((timer.id, err));
+                return false;
+            }
+            timer.next += timer.period;
+            if timer.next <= now {
+                timer.next = now + timer.period;
+            }
+            true
+        });
+        errors
+    }
+}
+
+enum Command {
+    Start {
+        id: MappingKey,
+        delay: Duration,
+        period: Duration,
+        task: Task,
+    },
+    Stop(MappingKey, Sender<()>),
+}
+
+/// A single thread running all the repeat timers.
+pub struct Scheduler {
+    sender: Option<Sender<Command>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || Scheduler::run(rx));
+        Scheduler {
+            sender: Some(tx),
+            thread: Some(thread),
+        }
+    }
+
+    fn run(rx: Receiver<Command>) {
+        let mut timers = Timers::default();
+        loop {
+            let command = match timers.next_deadline() {
+                Some(deadline) => {
+                    rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match command {
+                Ok(Command::Start {
+                    id,
+                    delay,
+                    period,
+                    task,
+                }) => timers.insert(id, Instant::now(), delay, period, task),
+                Ok(Command::Stop(id, ack)) => {
+                    timers.remove(id);
+                    let _ = ack.send(());
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    for ((device, key), err) in timers.run_due(Instant::now()) {
+                        println!("Repeat for scancode {} on {} stopped: {}", key, device, err);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+
+    fn send(&self, command: Command) {
+        if let Some(sender) = &self.sender {
+            // the thread only exits once the sender is dropped
+            sender.send(command).unwrap();
+        }
+    }
+
+    pub fn start(&self, id: MappingKey, delay: Duration, period: Duration, task: Task) {
+        self.send(Command::Start {
+            id,
+            delay,
+            period,
+            task,
+        });
+    }
+
+    /// Cancels the timer, returning only once it is guaranteed not to run anymore.
+    pub fn stop(&self, id: MappingKey) {
+        let (tx, rx) = mpsc::channel();
+        self.send(Command::Stop(id, tx));
+        let _ = rx.recv();
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use windows::core::Error as WinError;
+    use windows::Win32::Foundation::E_FAIL;
+
+    use super::{Task, Timers};
+
+    const DELAY: Duration = Duration::from_millis(500);
+    const PERIOD: Duration = Duration::from_millis(30);
+
+    fn counter() -> (Arc<AtomicUsize>, Task) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let task_count = count.clone();
+        let task: Task = Box::new(move || {
+            task_count.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        (count, task)
+    }
+
+    #[test]
+    fn runs_after_the_delay_then_every_period() {
+        let start = Instant::now();
+        let mut timers = Timers::default();
+        let (count, task) = counter();
+        timers.insert(1, start, DELAY, PERIOD, task);
+        assert_eq!(timers.next_deadline(), Some(start + DELAY));
+        assert!(timers.run_due(start + DELAY - PERIOD).is_empty());
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        timers.run_due(start + DELAY);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert_eq!(timers.next_deadline(), Some(start + DELAY + PERIOD));
+        timers.run_due(start + DELAY + PERIOD);
+        timers.run_due(start + DELAY + PERIOD * 2);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn late_timers_fire_once() {
+        let start = Instant::now();
+        let mut timers = Timers::default();
+        let (count, task) = counter();
+        timers.insert(1, start, DELAY, PERIOD, task);
+        let late = start + DELAY + PERIOD * 10;
+        timers.run_due(late);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert_eq!(timers.next_deadline(), Some(late + PERIOD));
+    }
+
+    #[test]
+    fn insert_replaces_and_remove_cancels() {
+        let start = Instant::now();
+        let mut timers = Timers::default();
+        let (first, task) = counter();
+        timers.insert(1, start, DELAY, PERIOD, task);
+        let (second, task) = counter();
+        timers.insert(1, start, PERIOD, PERIOD, task);
+        timers.run_due(start + DELAY);
+        assert_eq!(first.load(Ordering::SeqCst), 0);
+        assert_eq!(second.load(Ordering::SeqCst), 1);
+        assert!(timers.remove(1));
+        assert!(!timers.remove(1));
+        assert_eq!(timers.next_deadline(), None);
+    }
+
+    #[test]
+    fn failing_timers_are_removed() {
+        let start = Instant::now();
+        let mut timers = Timers::default();
+        timers.insert(
+            1,
+            start,
+            DELAY,
+            PERIOD,
+            Box::new(|| Err(WinError::from(E_FAIL))),
+        );
+        let (count, task) = counter();
+        timers.insert(2, start, DELAY, PERIOD, task);
+        let errors = timers.run_due(start + DELAY);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 1);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        timers.run_due(start + DELAY + PERIOD);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert!(!timers.remove(1));
+    }
+}
@@ -0,0 +1,86 @@
+use std::panic;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use windows::Win32::Foundation::BOOL;
+use windows::Win32::System::Console::SetConsoleCtrlHandler;
+
+use crate::output;
+
+/// How long the console handler waits for the main thread to clean up. Windows ends the
+/// process 5 seconds after the console is closed.
+const CLEANUP_TIMEOUT: Duration = Duration::from_millis(4500);
+
+/// A flag that threads can wait on.
+#[derive(Clone, Default)]
+pub struct StopSignal(Arc<(Mutex<bool>, Condvar)>);
+
+impl StopSignal {
+    fn lock(&self) -> MutexGuard<'_, bool> {
+        self.0 .0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn stop(&self) {
+        *self.lock() = true;
+        self.0 .1.notify_all();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        *self.lock()
+    }
+
+    /// Waits for `timeout` unless stopped before, returning whether it was stopped.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let (stopped, _) = self
+            .0
+             .1
+            .wait_timeout_while(self.lock(), timeout, |stopped| !*stopped)
+            .unwrap_or_else(PoisonError::into_inner);
+        *stopped
+    }
+}
+
+lazy_static! {
+    /// Set when usbrawmap is asked to stop.
+    static ref REQUESTED: StopSignal = StopSignal::default();
+}
+
+/// Whether usbrawmap was asked to stop with Ctrl+C or by closing its console.
+pub fn requested() -> bool {
+    REQUESTED.is_stopped()
+}
+
+/// Releases the keys and buttons held by the mappings when dropped, so that an error doesn't
+/// leave Ctrl or Win stuck system-wide.
+pub struct ReleaseGuard;
+
+impl Drop for ReleaseGuard {
+    fn drop(&mut self) {
+        output::release_all();
+    }
+}
+
+unsafe extern "system" fn on_console_event(_ctrl_type: u32) -> BOOL {
+    println!("Stopping");
+    REQUESTED.stop();
+    // the process ends as soon as the main thread is done, so this only returns if it hangs
+    std::thread::sleep(CLEANUP_TIMEOUT);
+    output::release_all();
+    // let the default handler end the process
+    false.into()
+}
+
+/// Stops usbrawmap cleanly on Ctrl+C or when its console is closed, and releases the held
+/// keys when it stops, including when a thread panics.
+pub fn install() -> ReleaseGuard {
+    unsafe {
+        SetConsoleCtrlHandler(Some(on_console_event), true);
+    }
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        output::release_all();
+        default_hook(info);
+    }));
+    ReleaseGuard
+}
@@ -0,0 +1,450 @@
+use std::fmt;
+use std::io;
+
+use crate::device::DeviceIds;
+
+/// Size of `USB_NODE_INFORMATION`.
+pub const NODE_INFORMATION_SIZE: usize = 76;
+/// Size of `USB_NODE_CONNECTION_INFORMATION_EX` without its pipe list.
+pub const CONNECTION_INFORMATION_SIZE: usize = 35;
+/// Size of the `ConnectionIndex` and `ActualLength` fields of `USB_NODE_CONNECTION_NAME`.
+pub const CONNECTION_NAME_HEADER_SIZE: usize = 8;
+
+const USB_HUB: u32 = 0;
+const DEVICE_CONNECTED: u32 = 1;
+/// USB allows 5 hubs between a device and the root hub.
+const MAX_HUB_DEPTH: usize = 5;
+
+/// The USB hub IOCTLs, separated from the Win32 calls so that the discovery doesn't depend on
+/// actual hubs. Each call returns the raw output buffer of the IOCTL.
+pub trait UsbBackend {
+    /// The names of the MS-DOS devices, among which are the USBPcap control devices.
+    fn dos_devices(&self) -> io::Result<Vec<String>>;
+
+    /// `IOCTL_USBPCAP_GET_HUB_SYMLINK` on `\\.\USBPcap{driver}`.
+    fn hub_symlink(&self, driver: u8) -> io::Result<Vec<u8>>;
+
+    /// `IOCTL_USB_GET_NODE_INFORMATION` on the hub at `path`.
+    fn node_information(&self, path: &str) -> io::Result<Vec<u8>>;
+
+    /// `IOCTL_USB_GET_NODE_CONNECTION_INFORMATION_EX` for a port of the hub at `path`.
+    fn connection_information(&self, path: &str, port: u32) -> io::Result<Vec<u8>>;
+
+    /// `IOCTL_USB_GET_NODE_CONNECTION_NAME` for a port of the hub at `path`.
+    fn connection_name(&self, path: &str, port: u32) -> io::Result<Vec<u8>>;
+}
+
+/// What a hub reports about one of its ports.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Connection {
+    pub address: u16,
+    pub ids: DeviceIds,
+    pub is_hub: bool,
+}
+
+/// A device plugged into a hub port.
+pub struct UsbDevice {
+    pub port: u32,
+    pub connection: Connection,
+    /// The devices plugged into it, if it is a hub.
+    pub children: Vec<UsbDevice>,
+}
+
+/// The root hub captured by a USBPcap control device.
+pub struct RootHub {
+    pub driver: u8,
+    pub path: Option<String>,
+    pub devices: io::Result<Vec<UsbDevice>>,
+}
+
+/// The USBPcap driver numbers, from the names of the `USBPcapN` control devices.
+pub fn parse_control_devices(names: &[String]) -> Vec<u8> {
+    let mut drivers: Vec<u8> = names
+        .iter()
+        .filter_map(|name| name.strip_prefix("USBPcap")?.parse().ok())
+        .collect();
+    drivers.sort_unstable();
+    drivers.dedup();
+    drivers
+}
+
+/// A UTF-16 string, up to its first null character.
+pub fn parse_wide_string(data: &[u8]) -> String {
+    let chars: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0)
+        .collect();
+    String::from_utf16_lossy(&chars)
+}
+
+/// The number of ports, from a `USB_NODE_INFORMATION`.
+pub fn parse_port_count(data: &[u8]) -> Option<u8> {
+    if data.len() < NODE_INFORMATION_SIZE {
+        return None;
+    }
+    let node_type = u32::from_le_bytes(data[0..4].try_into().ok()?);
+    // bNumberOfPorts, after the bDescriptorLength and bDescriptorType of the hub descriptor
+    (node_type == USB_HUB).then_some(data[6])
+}
+
+/// The device on a port, from a `USB_NODE_CONNECTION_INFORMATION_EX`, if there is one.
+pub fn parse_connection(data: &[u8]) -> Option<Connection> {
+    if data.len() < CONNECTION_INFORMATION_SIZE {
+        return None;
+    }
+    let u16_at = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+    let status = u32::from_le_bytes(data[31..35].try_into().ok()?);
+    if status != DEVICE_CONNECTED {
+        return None;
+    }
+    Some(Connection {
+        // idVendor and idProduct of the device descriptor, which starts at offset 4
+        ids: DeviceIds {
+            vid: u16_at(12),
+            pid: u16_at(14),
+        },
+        is_hub: data[24] != 0,
+        address: u16_at(25),
+    })
+}
+
+/// The name of the hub on a port, from a `USB_NODE_CONNECTION_NAME`.
+pub fn parse_connection_name(data: &[u8]) -> Option<String> {
+    let name = parse_wide_string(data.get(CONNECTION_NAME_HEADER_SIZE..)?);
+    (!name.is_empty()).then_some(name)
+}
+
+/// The path to open a hub from its symbolic link or device name.
+pub fn device_path(name: &str) -> String {
+    let name = name
+        .strip_prefix(r"\??\")
+        .or_else(|| name.strip_prefix(r"\\.\"))
+        .unwrap_or(name);
+    format!(r"\\.\{}", name)
+}
+
+fn hub_devices<B: UsbBackend>(backend: &B, path: &str, depth: usize) -> io::Result<Vec<UsbDevice>> {
+    let ports = parse_port_count(&backend.node_information(path)?)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Not a hub"))?;
+    let mut devices = Vec::new();
+    for port in 1..=ports as u32 {
+        let connection = match parse_connection(&backend.connection_information(path, port)?) {
+            Some(connection) => connection,
+            None => continue,
+        };
+        let mut children = Vec::new();
+        if connection.is_hub && depth < MAX_HUB_DEPTH {
+            // a hub that can't be opened is listed without its devices
+            if let Some(name) = backend
+                .connection_name(path, port)
+                .ok()
+                .and_then(|data| parse_connection_name(&data))
+            {
+                children = hub_devices(backend, &device_path(&name), depth + 1).unwrap_or_default();
+            }
+        }
+        devices.push(UsbDevice {
+            port,
+            connection,
+            children,
+        });
+    }
+    Ok(devices)
+}
+
+/// The root hub captured by a USBPcap control device, with the devices plugged into it.
+pub fn root_hub<B: UsbBackend>(backend: &B, driver: u8) -> RootHub {
+    match backend.hub_symlink(driver) {
+        Ok(data) => {
+            let path = device_path(&parse_wide_string(&data));
+            RootHub {
+                driver,
+                devices: hub_devices(backend, &path, 0),
+                path: Some(path),
+            }
+        }
+        Err(err) => RootHub {
+            driver,
+            path: None,
+            devices: Err(err),
+        },
+    }
+}
+
+/// The root hubs captured by USBPcap, with the devices plugged into them.
+pub fn discover<B: UsbBackend>(backend: &B) -> io::Result<Vec<RootHub>> {
+    Ok(parse_control_devices(&backend.dos_devices()?)
+        .into_iter()
+        .map(|driver| root_hub(backend, driver))
+        .collect())
+}
+
+impl UsbDevice {
+    fn any(&self, matches: &impl Fn(DeviceIds) -> bool) -> bool {
+        matches(self.connection.ids) || self.children.iter().any(|child| child.any(matches))
+    }
+
+    fn collect_connections(&self, connections: &mut Vec<Connection>) {
+        connections.push(self.connection);
+        for child in &self.children {
+            child.collect_connections(connections);
+        }
+    }
+
+    fn fmt_tree(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        writeln!(
+            f,
+            "{:indent$}Port {}: {} (device {}{})",
+            "",
+            self.port,
+            self.connection.ids,
+            self.connection.address,
+            if self.connection.is_hub { ", hub" } else { "" },
+            indent = depth * 2
+        )?;
+        for child in &self.children {
+            child.fmt_tree(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl RootHub {
+    /// Whether a device matching `matches` is plugged into the hub or one of its hubs.
+    pub fn contains(&self, matches: impl Fn(DeviceIds) -> bool) -> bool {
+        match &self.devices {
+            Ok(devices) => devices.iter().any(|device| device.any(&matches)),
+            Err(_) => false,
+        }
+    }
+
+    /// The devices plugged into the hub and its hubs, including the hubs.
+    pub fn connections(&self) -> Vec<Connection> {
+        let mut connections = Vec::new();
+        for device in self.devices.iter().flatten() {
+            device.collect_connections(&mut connections);
+        }
+        connections
+    }
+}
+
+/// `USBPcapN: path`, followed by the tree of devices.
+impl fmt::Display for RootHub {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "USBPcap{}", self.driver)?;
+        if let Some(path) = &self.path {
+            write!(f, ": {}", path)?;
+        }
+        match &self.devices {
+            Ok(devices) => {
+                writeln!(f)?;
+                for device in devices {
+                    device.fmt_tree(f, 1)?;
+                }
+                Ok(())
+            }
+            Err(err) => writeln!(f, " ({})", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io;
+
+    use super::*;
+
+    fn wide(s: &str) -> Vec<u8> {
+        s.encode_utf16()
+            .chain([0])
+            .flat_map(|c| c.to_le_bytes())
+            .collect()
+    }
+
+    fn node_information(node_type: u32, ports: u8) -> Vec<u8> {
+        let mut data = vec![0; NODE_INFORMATION_SIZE];
+        data[0..4].copy_from_slice(&node_type.to_le_bytes());
+        data[6] = ports;
+        data
+    }
+
+    fn connection_information(connection: Option<Connection>) -> Vec<u8> {
+        let mut data = vec![0; CONNECTION_INFORMATION_SIZE];
+        if let Some(connection) = connection {
+            data[12..14].copy_from_slice(&connection.ids.vid.to_le_bytes());
+            data[14..16].copy_from_slice(&connection.ids.pid.to_le_bytes());
+            data[24] = connection.is_hub as u8;
+            data[25..27].copy_from_slice(&connection.address.to_le_bytes());
+            data[31..35].copy_from_slice(&DEVICE_CONNECTED.to_le_bytes());
+        }
+        data
+    }
+
+    fn connection(address: u16, vid: u16, pid: u16, is_hub: bool) -> Connection {
+        Connection {
+            address,
+            ids: DeviceIds { vid, pid },
+            is_hub,
+        }
+    }
+
+    /// The devices on each port of a hub, with the name of the hubs.
+    type Ports = Vec<Option<(Connection, Option<&'static str>)>>;
+
+    /// Hubs given by their path, with the symbolic links of the USBPcap control devices.
+    #[derive(Default)]
+    struct FakeBackend {
+        symlinks: HashMap<u8, &'static str>,
+        hubs: HashMap<&'static str, Ports>,
+    }
+
+    impl FakeBackend {
+        fn port(&self, path: &str, port: u32) -> io::Result<&Option<(Connection, Option<&str>)>> {
+            self.hubs
+                .get(path)
+                .and_then(|ports| ports.get(port as usize - 1))
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        }
+    }
+
+    impl UsbBackend for FakeBackend {
+        fn dos_devices(&self) -> io::Result<Vec<String>> {
+            let mut names: Vec<String> = self
+                .symlinks
+                .keys()
+                .map(|driver| format!("USBPcap{}", driver))
+                .collect();
+            names.extend(["COM1".to_string(), "USBPcap".to_string()]);
+            Ok(names)
+        }
+
+        fn hub_symlink(&self, driver: u8) -> io::Result<Vec<u8>> {
+            match self.symlinks.get(&driver) {
+                Some(&"") => Err(io::ErrorKind::PermissionDenied.into()),
+                Some(symlink) => Ok(wide(symlink)),
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+
+        fn node_information(&self, path: &str) -> io::Result<Vec<u8>> {
+            match self.hubs.get(path) {
+                Some(ports) => Ok(node_information(USB_HUB, ports.len() as u8)),
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+
+        fn connection_information(&self, path: &str, port: u32) -> io::Result<Vec<u8>> {
+            Ok(connection_information(
+                self.port(path, port)?.map(|(connection, _)| connection),
+            ))
+        }
+
+        fn connection_name(&self, path: &str, port: u32) -> io::Result<Vec<u8>> {
+            match self.port(path, port)? {
+                Some((_, Some(name))) => {
+                    let mut data = vec![0; CONNECTION_NAME_HEADER_SIZE];
+                    data.extend(wide(name));
+                    Ok(data)
+                }
+                _ => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+    }
+
+    #[test]
+    fn parses_control_devices() {
+        let names = ["USBPcap3", "COM1", "USBPcap1", "USBPcapX", "USBPcap3"].map(String::from);
+        assert_eq!(parse_control_devices(&names), vec![1, 3]);
+    }
+
+    #[test]
+    fn parses_port_count() {
+        assert_eq!(parse_port_count(&node_information(USB_HUB, 4)), Some(4));
+        // a USB_MI_PARENT node
+        assert_eq!(parse_port_count(&node_information(1, 4)), None);
+        assert_eq!(
+            parse_port_count(&node_information(USB_HUB, 4)[..NODE_INFORMATION_SIZE - 1]),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_connection() {
+        let keyboard = connection(3, 0x0430, 0x0005, false);
+        assert_eq!(
+            parse_connection(&connection_information(Some(keyboard))),
+            Some(keyboard)
+        );
+        let hub = connection(0x1234, 0x05e3, 0x0610, true);
+        assert_eq!(
+            parse_connection(&connection_information(Some(hub))),
+            Some(hub)
+        );
+        // an empty port
+        assert_eq!(parse_connection(&connection_information(None)), None);
+        let data = connection_information(Some(keyboard));
+        assert_eq!(
+            parse_connection(&data[..CONNECTION_INFORMATION_SIZE - 1]),
+            None
+        );
+    }
+
+    #[test]
+    fn parses_names() {
+        assert_eq!(parse_wide_string(&wide("USB#ROOT_HUB30")), "USB#ROOT_HUB30");
+        assert_eq!(
+            parse_connection_name(&[0; CONNECTION_NAME_HEADER_SIZE]),
+            None
+        );
+        assert_eq!(parse_connection_name(&[0; 4]), None);
+        assert_eq!(device_path(r"\??\USB#ROOT_HUB30"), r"\\.\USB#ROOT_HUB30");
+        assert_eq!(device_path(r"\\.\USB#ROOT_HUB30"), r"\\.\USB#ROOT_HUB30");
+        assert_eq!(device_path("USB#VID_05E3"), r"\\.\USB#VID_05E3");
+    }
+
+    #[test]
+    fn discovers_the_devices_of_each_hub() {
+        let keyboard = connection(3, 0x0430, 0x0005, false);
+        let hub = connection(4, 0x05e3, 0x0610, true);
+        let pad = connection(5, 0x1209, 0x0001, false);
+        let mut backend = FakeBackend::default();
+        backend.symlinks.insert(1, r"\??\USB#ROOT_HUB30");
+        backend.symlinks.insert(2, "");
+        backend.hubs.insert(
+            r"\\.\USB#ROOT_HUB30",
+            vec![Some((keyboard, None)), None, Some((hub, Some("USB#HUB")))],
+        );
+        backend
+            .hubs
+            .insert(r"\\.\USB#HUB", vec![None, Some((pad, None))]);
+
+        let hubs = discover(&backend).unwrap();
+        assert_eq!(hubs.len(), 2);
+        assert_eq!(hubs[0].driver, 1);
+        assert_eq!(hubs[0].path.as_deref(), Some(r"\\.\USB#ROOT_HUB30"));
+        let devices = hubs[0].devices.as_ref().unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!((devices[0].port, devices[0].connection), (1, keyboard));
+        assert!(devices[0].children.is_empty());
+        assert_eq!((devices[1].port, devices[1].connection), (3, hub));
+        assert_eq!(devices[1].children.len(), 1);
+        assert_eq!(
+            (
+                devices[1].children[0].port,
+                devices[1].children[0].connection
+            ),
+            (2, pad)
+        );
+        assert!(hubs[0].contains(|ids| ids == pad.ids));
+        assert!(!hubs[0].contains(|ids| ids.vid == 0xffff));
+        assert_eq!(hubs[0].connections(), vec![keyboard, hub, pad]);
+
+        assert_eq!(hubs[1].driver, 2);
+        assert!(hubs[1].path.is_none());
+        assert!(hubs[1].devices.is_err());
+        assert!(!hubs[1].contains(|_| true));
+        assert!(hubs[1].connections().is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+use std::ffi::c_void;
+use std::{io, ptr};
+use widestring::WideCString;
+
+use windows::core::{Error as WinError, PCWSTR};
+use windows::Win32::Foundation::{CloseHandle, HANDLE};
+use windows::Win32::Storage::FileSystem::{
+    CreateFileW, QueryDosDeviceW, FILE_ACCESS_FLAGS, FILE_FLAGS_AND_ATTRIBUTES, FILE_GENERIC_WRITE,
+    FILE_SHARE_MODE, FILE_SHARE_NONE, FILE_SHARE_WRITE, OPEN_EXISTING,
+};
+use windows::Win32::System::IO::DeviceIoControl;
+
+use crate::ioctl::{
+    IOCTL_USBPCAP_GET_HUB_SYMLINK, IOCTL_USB_GET_NODE_CONNECTION_INFORMATION_EX,
+    IOCTL_USB_GET_NODE_CONNECTION_NAME, IOCTL_USB_GET_NODE_INFORMATION,
+};
+use crate::topology::{
+    UsbBackend, CONNECTION_INFORMATION_SIZE, CONNECTION_NAME_HEADER_SIZE, NODE_INFORMATION_SIZE,
+};
+
+/// Room for the pipes of a device in `USB_NODE_CONNECTION_INFORMATION_EX`, 11 bytes each.
+const PIPE_LIST_SIZE: usize = 32 * 11;
+/// Room for device names and symbolic links.
+const NAME_SIZE: usize = 1024;
+
+fn to_io_error(err: WinError) -> io::Error {
+    io::Error::from_raw_os_error(err.code().0)
+}
+
+struct Device(HANDLE);
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.0);
+        }
+    }
+}
+
+impl Device {
+    fn open(path: &str, access: FILE_ACCESS_FLAGS, share: FILE_SHARE_MODE) -> io::Result<Device> {
+        let path = WideCString::from_str_truncate(path);
+        unsafe {
+            CreateFileW(
+                PCWSTR(path.as_ptr()),
+                access,
+                share,
+                ptr::null_mut(),
+                OPEN_EXISTING,
+                FILE_FLAGS_AND_ATTRIBUTES(0),
+                HANDLE::default(),
+            )
+        }
+        .map(Device)
+        .map_err(to_io_error)
+    }
+
+    /// Sends `input` and returns the bytes written to an output buffer of `output_size` bytes.
+    fn ioctl(&self, code: u32, input: &[u8], output_size: usize) -> io::Result<Vec<u8>> {
+        let mut output = vec![0u8; output_size];
+        let mut returned = 0;
+        unsafe {
+            DeviceIoControl(
+                self.0,
+                code,
+                input.as_ptr() as *const c_void,
+                input.len() as u32,
+                output.as_mut_ptr() as *mut c_void,
+                output.len() as u32,
+                &mut returned,
+                ptr::null_mut(),
+            )
+        }
+        .ok()
+        .map_err(to_io_error)?;
+        output.truncate(returned as usize);
+        Ok(output)
+    }
+}
+
+/// The actual hubs, through the Win32 API.
+pub struct Win32Backend;
+
+impl Win32Backend {
+    fn hub(path: &str) -> io::Result<Device> {
+        Device::open(path, FILE_GENERIC_WRITE, FILE_SHARE_WRITE)
+    }
+
+    /// The input buffer of the per-port IOCTLs, which starts with the port number.
+    fn port_input(port: u32, size: usize) -> Vec<u8> {
+        let mut input = vec![0u8; size];
+        input[..4].copy_from_slice(&port.to_le_bytes());
+        input
+    }
+}
+
+impl UsbBackend for Win32Backend {
+    fn dos_devices(&self) -> io::Result<Vec<String>> {
+        let mut names = vec![0u16; 1 << 16];
+        let len = unsafe { QueryDosDeviceW(PCWSTR(ptr::null()), &mut names) };
+        if len == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(String::from_utf16_lossy(&names[..len as usize])
+            .split('\0')
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn hub_symlink(&self, driver: u8) -> io::Result<Vec<u8>> {
+        let path = format!(r"\\.\USBPcap{}", driver);
+        Device::open(&path, FILE_ACCESS_FLAGS(0), FILE_SHARE_NONE)?.ioctl(
+            IOCTL_USBPCAP_GET_HUB_SYMLINK,
+            &[],
+            NAME_SIZE,
+        )
+    }
+
+    fn node_information(&self, path: &str) -> io::Result<Vec<u8>> {
+        let input = [0u8; NODE_INFORMATION_SIZE];
+        Self::hub(path)?.ioctl(
+            IOCTL_USB_GET_NODE_INFORMATION,
+            &input,
+            NODE_INFORMATION_SIZE,
+        )
+    }
+
+    fn connection_information(&self, path: &str, port: u32) -> io::Result<Vec<u8>> {
+        let size = CONNECTION_INFORMATION_SIZE + PIPE_LIST_SIZE;
+        Self::hub(path)?.ioctl(
+            IOCTL_USB_GET_NODE_CONNECTION_INFORMATION_EX,
+            &Self::port_input(port, size),
+            size,
+        )
+    }
+
+    fn connection_name(&self, path: &str, port: u32) -> io::Result<Vec<u8>> {
+        let size = CONNECTION_NAME_HEADER_SIZE + NAME_SIZE;
+        Self::hub(path)?.ioctl(
+            IOCTL_USB_GET_NODE_CONNECTION_NAME,
+            &Self::port_input(port, size),
+            size,
+        )
+    }
+}
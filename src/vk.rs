@@ -1,24 +1,28 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::sync::mpsc;
-use std::sync::mpsc::Sender;
-use std::thread;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
 use lazy_static::lazy_static;
 use phf::phf_map;
-use serde::de::Error;
+use serde::de::{Error, SeqAccess, Visitor};
 use serde::{Deserialize, Deserializer};
+use thiserror::Error;
 use windows::core::Error as WinError;
 use windows::Win32::UI::Input::KeyboardAndMouse::{
-    SendInput, INPUT, INPUT_0, INPUT_KEYBOARD, KEYBDINPUT, KEYBD_EVENT_FLAGS, KEYEVENTF_KEYUP,
-    VIRTUAL_KEY,
+    INPUT, INPUT_0, INPUT_KEYBOARD, KEYBDINPUT, KEYBD_EVENT_FLAGS, KEYEVENTF_EXTENDEDKEY,
+    KEYEVENTF_KEYUP, KEYEVENTF_SCANCODE, VIRTUAL_KEY,
 };
 use windows::Win32::UI::WindowsAndMessaging::{
     SystemParametersInfoW, SPI_GETKEYBOARDDELAY, SPI_GETKEYBOARDSPEED,
     SYSTEM_PARAMETERS_INFO_UPDATE_FLAGS,
 };
 
+use crate::mappings::MappingKey;
+use crate::mouse::MouseAction;
+use crate::output::{InputEvent, Output, SendInputOutput, HELD};
+use crate::scheduler::Scheduler;
+
 static VK_MAP: phf::Map<&str, u8> = phf_map! {
     "LBUTTON" => 0x01, // Left mouse button
     "RBUTTON" => 0x02, // Right mouse button
@@ -194,31 +198,392 @@ static VK_MAP: phf::Map<&str, u8> = phf_map! {
     "OEM_CLEAR" => 0xFE, // Clear key
 };
 
+/// Common names for keys whose [`VK_MAP`] name is less obvious.
+static VK_ALIASES: phf::Map<&str, u8> = phf_map! {
+    "CTRL" => 0x11,
+    "LCTRL" => 0xA2,
+    "RCTRL" => 0xA3,
+    "ALT" => 0x12,
+    "LALT" => 0xA4,
+    "RALT" => 0xA5,
+    "ALTGR" => 0xA5,
+    "WIN" => 0x5B,
+    "ENTER" => 0x0D,
+    "ESC" => 0x1B,
+    "BACKSPACE" => 0x08,
+    "PGUP" => 0x21,
+    "PAGEUP" => 0x21,
+    "PGDN" => 0x22,
+    "PAGEDOWN" => 0x22,
+    "INS" => 0x2D,
+    "DEL" => 0x2E,
+    "CAPSLOCK" => 0x14,
+    "SCROLLLOCK" => 0x91,
+    "PRINTSCREEN" => 0x2C,
+    "PRTSC" => 0x2C,
+};
+
+/// Set 1 scan codes, extended keys have the 0xE0 prefix.
+static SCAN_CODES: phf::Map<u8, u16> = phf_map! {
+    0x08u8 => 0x0E, // BACK
+    0x09u8 => 0x0F, // TAB
+    0x0Du8 => 0x1C, // RETURN
+    0x10u8 => 0x2A, // SHIFT
+    0x11u8 => 0x1D, // CONTROL
+    0x12u8 => 0x38, // MENU
+    0x14u8 => 0x3A, // CAPITAL
+    0x1Bu8 => 0x01, // ESCAPE
+    0x20u8 => 0x39, // SPACE
+    0x21u8 => 0xE049, // PRIOR
+    0x22u8 => 0xE051, // NEXT
+    0x23u8 => 0xE04F, // END
+    0x24u8 => 0xE047, // HOME
+    0x25u8 => 0xE04B, // LEFT
+    0x26u8 => 0xE048, // UP
+    0x27u8 => 0xE04D, // RIGHT
+    0x28u8 => 0xE050, // DOWN
+    0x2Cu8 => 0xE037, // SNAPSHOT
+    0x2Du8 => 0xE052, // INSERT
+    0x2Eu8 => 0xE053, // DELETE
+    0x30u8 => 0x0B, // 0
+    0x31u8 => 0x02, // 1
+    0x32u8 => 0x03, // 2
+    0x33u8 => 0x04, // 3
+    0x34u8 => 0x05, // 4
+    0x35u8 => 0x06, // 5
+    0x36u8 => 0x07, // 6
+    0x37u8 => 0x08, // 7
+    0x38u8 => 0x09, // 8
+    0x39u8 => 0x0A, // 9
+    0x41u8 => 0x1E, // A
+    0x42u8 => 0x30, // B
+    0x43u8 => 0x2E, // C
+    0x44u8 => 0x20, // D
+    0x45u8 => 0x12, // E
+    0x46u8 => 0x21, // F
+    0x47u8 => 0x22, // G
+    0x48u8 => 0x23, // H
+    0x49u8 => 0x17, // I
+    0x4Au8 => 0x24, // J
+    0x4Bu8 => 0x25, // K
+    0x4Cu8 => 0x26, // L
+    0x4Du8 => 0x32, // M
+    0x4Eu8 => 0x31, // N
+    0x4Fu8 => 0x18, // O
+    0x50u8 => 0x19, // P
+    0x51u8 => 0x10, // Q
+    0x52u8 => 0x13, // R
+    0x53u8 => 0x1F, // S
+    0x54u8 => 0x14, // T
+    0x55u8 => 0x16, // U
+    0x56u8 => 0x2F, // V
+    0x57u8 => 0x11, // W
+    0x58u8 => 0x2D, // X
+    0x59u8 => 0x15, // Y
+    0x5Au8 => 0x2C, // Z
+    0x5Bu8 => 0xE05B, // LWIN
+    0x5Cu8 => 0xE05C, // RWIN
+    0x5Du8 => 0xE05D, // APPS
+    0x5Fu8 => 0xE05F, // SLEEP
+    0x60u8 => 0x52, // NUMPAD0
+    0x61u8 => 0x4F, // NUMPAD1
+    0x62u8 => 0x50, // NUMPAD2
+    0x63u8 => 0x51, // NUMPAD3
+    0x64u8 => 0x4B, // NUMPAD4
+    0x65u8 => 0x4C, // NUMPAD5
+    0x66u8 => 0x4D, // NUMPAD6
+    0x67u8 => 0x47, // NUMPAD7
+    0x68u8 => 0x48, // NUMPAD8
+    0x69u8 => 0x49, // NUMPAD9
+    0x6Au8 => 0x37, // MULTIPLY
+    0x6Bu8 => 0x4E, // ADD
+    0x6Du8 => 0x4A, // SUBTRACT
+    0x6Eu8 => 0x53, // DECIMAL
+    0x6Fu8 => 0xE035, // DIVIDE
+    0x70u8 => 0x3B, // F1
+    0x71u8 => 0x3C, // F2
+    0x72u8 => 0x3D, // F3
+    0x73u8 => 0x3E, // F4
+    0x74u8 => 0x3F, // F5
+    0x75u8 => 0x40, // F6
+    0x76u8 => 0x41, // F7
+    0x77u8 => 0x42, // F8
+    0x78u8 => 0x43, // F9
+    0x79u8 => 0x44, // F10
+    0x7Au8 => 0x57, // F11
+    0x7Bu8 => 0x58, // F12
+    0x7Cu8 => 0x64, // F13
+    0x7Du8 => 0x65, // F14
+    0x7Eu8 => 0x66, // F15
+    0x7Fu8 => 0x67, // F16
+    0x80u8 => 0x68, // F17
+    0x81u8 => 0x69, // F18
+    0x82u8 => 0x6A, // F19
+    0x83u8 => 0x6B, // F20
+    0x84u8 => 0x6C, // F21
+    0x85u8 => 0x6D, // F22
+    0x86u8 => 0x6E, // F23
+    0x87u8 => 0x76, // F24
+    0x90u8 => 0xE045, // NUMLOCK
+    0x91u8 => 0x46, // SCROLL
+    0xA0u8 => 0x2A, // LSHIFT
+    0xA1u8 => 0x36, // RSHIFT
+    0xA2u8 => 0x1D, // LCONTROL
+    0xA3u8 => 0xE01D, // RCONTROL
+    0xA4u8 => 0x38, // LMENU
+    0xA5u8 => 0xE038, // RMENU
+    0xA6u8 => 0xE06A, // BROWSER_BACK
+    0xA7u8 => 0xE069, // BROWSER_FORWARD
+    0xA8u8 => 0xE067, // BROWSER_REFRESH
+    0xA9u8 => 0xE068, // BROWSER_STOP
+    0xAAu8 => 0xE065, // BROWSER_SEARCH
+    0xABu8 => 0xE066, // BROWSER_FAVORITES
+    0xACu8 => 0xE032, // BROWSER_HOME
+    0xADu8 => 0xE020, // VOLUME_MUTE
+    0xAEu8 => 0xE02E, // VOLUME_DOWN
+    0xAFu8 => 0xE030, // VOLUME_UP
+    0xB0u8 => 0xE019, // MEDIA_NEXT_TRACK
+    0xB1u8 => 0xE010, // MEDIA_PREV_TRACK
+    0xB2u8 => 0xE024, // MEDIA_STOP
+    0xB3u8 => 0xE022, // MEDIA_PLAY_PAUSE
+    0xB4u8 => 0xE06C, // LAUNCH_MAIL
+    0xB5u8 => 0xE06D, // LAUNCH_MEDIA_SELECT
+    0xB6u8 => 0xE06B, // LAUNCH_APP1
+    0xB7u8 => 0xE021, // LAUNCH_APP2
+    0xBAu8 => 0x27, // OEM_1
+    0xBBu8 => 0x0D, // OEM_PLUS
+    0xBCu8 => 0x33, // OEM_COMMA
+    0xBDu8 => 0x0C, // OEM_MINUS
+    0xBEu8 => 0x34, // OEM_PERIOD
+    0xBFu8 => 0x35, // OEM_2
+    0xC0u8 => 0x29, // OEM_3
+    0xDBu8 => 0x1A, // OEM_4
+    0xDCu8 => 0x2B, // OEM_5
+    0xDDu8 => 0x1B, // OEM_6
+    0xDEu8 => 0x28, // OEM_7
+    0xE2u8 => 0x56, // OEM_102
+};
+
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
 pub struct VK(u8);
 
-#[derive(Clone, Deserialize)]
+/// Keys pressed together, e.g. `Ctrl+Shift+Esc`.
+#[derive(Clone)]
 pub struct VKSeq(Vec<VK>);
 
+/// Combinations pressed one after the other, e.g. `Ctrl+K Ctrl+C`. The last one is held as long
+/// as the mapped key is.
+#[derive(Clone)]
+pub struct KeySequence(Vec<VKSeq>);
+
+#[derive(Error, Debug)]
+pub enum VKError {
+    #[error("Unknown VK: {0}{}", .1.map(|s| format!(" (did you mean {}?)", s)).unwrap_or_default())]
+    Unknown(String, Option<&'static str>),
+    #[error("VK code out of range: {0}")]
+    OutOfRange(i64),
+    #[error("Missing key name")]
+    Missing,
+}
+
+#[derive(Error, Debug)]
+#[error("{error}, at column {column} of \"{input}\"")]
+pub struct KeySequenceError {
+    input: String,
+    /// 1-based, in characters.
+    pub column: usize,
+    pub error: VKError,
+}
+
+impl VK {
+    /// Finds the closest known name, for typos.
+    fn suggest(name: &str) -> Option<&'static str> {
+        VK_MAP
+            .keys()
+            .chain(VK_ALIASES.keys())
+            .map(|candidate| (levenshtein(name, candidate), *candidate))
+            .filter(|(distance, _)| *distance <= (name.len() / 3).max(1))
+            .min()
+            .map(|(_, candidate)| candidate)
+    }
+}
+
+impl TryFrom<i64> for VK {
+    type Error = VKError;
+
+    fn try_from(code: i64) -> Result<Self, VKError> {
+        u8::try_from(code)
+            .ok()
+            .filter(|code| *code != 0)
+            .map(VK)
+            .ok_or(VKError::OutOfRange(code))
+    }
+}
+
+/// Accepts `0x` hex codes, and names from [`VK_MAP`] or [`VK_ALIASES`], case-insensitively and
+/// with an optional `VK_` prefix.
+impl FromStr for VK {
+    type Err = VKError;
+
+    fn from_str(s: &str) -> Result<Self, VKError> {
+        let s = s.trim();
+        if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            return match i64::from_str_radix(hex, 16) {
+                Ok(code) => VK::try_from(code),
+                Err(_) => Err(VKError::Unknown(s.to_string(), None)),
+            };
+        }
+        let upper = s.to_ascii_uppercase();
+        let name = upper.strip_prefix("VK_").unwrap_or(&upper);
+        VK_MAP
+            .get(name)
+            .or_else(|| VK_ALIASES.get(name))
+            .map(|vk| VK(*vk))
+            .ok_or_else(|| VKError::Unknown(s.to_string(), VK::suggest(name)))
+    }
+}
+
 impl<'de> Deserialize<'de> for VK {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let s = String::deserialize(deserializer)?;
-        match VK_MAP.get(&s) {
-            Some(vk) => Ok(VK(*vk)),
-            None => Err(D::Error::custom(format!("Unknown VK: {}", s))),
+        struct VKVisitor;
+
+        impl<'de> Visitor<'de> for VKVisitor {
+            type Value = VK;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a VK name or code")
+            }
+
+            fn visit_i64<E: Error>(self, v: i64) -> Result<VK, E> {
+                VK::try_from(v).map_err(E::custom)
+            }
+
+            fn visit_u64<E: Error>(self, v: u64) -> Result<VK, E> {
+                VK::try_from(v.min(i64::MAX as u64) as i64).map_err(E::custom)
+            }
+
+            fn visit_str<E: Error>(self, v: &str) -> Result<VK, E> {
+                v.parse().map_err(E::custom)
+            }
         }
+
+        deserializer.deserialize_any(VKVisitor)
     }
 }
 
+impl KeySequence {
+    pub fn keys(&self) -> impl Iterator<Item = &VK> {
+        self.0.iter().flat_map(|combo| combo.0.iter())
+    }
+}
+
+/// Parses `+`-separated combinations, themselves separated by spaces.
+impl FromStr for KeySequence {
+    type Err = KeySequenceError;
+
+    fn from_str(s: &str) -> Result<Self, KeySequenceError> {
+        let error = |token: &str, error| KeySequenceError {
+            input: s.to_string(),
+            column: s[..token.as_ptr() as usize - s.as_ptr() as usize]
+                .chars()
+                .count()
+                + 1,
+            error,
+        };
+        let combos = s
+            .split_whitespace()
+            .map(|combo| {
+                combo
+                    .split('+')
+                    .map(|name| match name {
+                        "" => Err(error(name, VKError::Missing)),
+                        _ => name.parse().map_err(|e| error(name, e)),
+                    })
+                    .collect::<Result<_, _>>()
+                    .map(VKSeq)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if combos.is_empty() {
+            return Err(error(s, VKError::Missing));
+        }
+        Ok(KeySequence(combos))
+    }
+}
+
+/// Accepts either a list of keys pressed together, or a string parsed by [`KeySequence::from_str`].
+impl<'de> Deserialize<'de> for KeySequence {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct KeySequenceVisitor;
+
+        impl<'de> Visitor<'de> for KeySequenceVisitor {
+            type Value = KeySequence;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a list of keys or a key combination string")
+            }
+
+            fn visit_str<E: Error>(self, v: &str) -> Result<KeySequence, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<KeySequence, A::Error> {
+                let mut keys = Vec::new();
+                while let Some(vk) = seq.next_element()? {
+                    keys.push(vk);
+                }
+                if keys.is_empty() {
+                    return Err(A::Error::custom(VKError::Missing));
+                }
+                Ok(KeySequence(vec![VKSeq(keys)]))
+            }
+        }
+
+        deserializer.deserialize_any(KeySequenceVisitor)
+    }
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                1 + prev.min(row[j]).min(cur)
+            };
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
 impl From<&VK> for VIRTUAL_KEY {
     fn from(vk: &VK) -> VIRTUAL_KEY {
         VIRTUAL_KEY(vk.0 as u16)
     }
 }
 
+/// How keys are injected: as virtual keys, or as the hardware scan codes some games and
+/// remote desktop clients expect.
+#[derive(Copy, Clone, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Injection {
+    #[default]
+    Vk,
+    Scancode,
+}
+
+#[derive(Copy, Clone)]
 pub enum KeyAction {
     Down,
     Up,
@@ -233,12 +598,34 @@ impl From<KeyAction> for KEYBD_EVENT_FLAGS {
     }
 }
 
-pub struct KeyRepeatThread {
-    channel: Sender<()>,
+#[derive(Copy, Clone, Deserialize)]
+pub struct RepeatDelays {
+    delay_ms: u64,
+    #[serde(deserialize_with = "deserialize_rate")]
+    rate_ms: u64,
+}
+
+/// Rejects a zero rate, which would repeat the keys as fast as the CPU allows.
+fn deserialize_rate<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match u64::deserialize(deserializer)? {
+        0 => Err(D::Error::custom("rate_ms must be at least 1")),
+        rate => Ok(rate),
+    }
+}
+
+/// `repeat = false | true | { delay_ms, rate_ms }`
+#[derive(Copy, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Repeat {
+    Enabled(bool),
+    Custom(RepeatDelays),
 }
 
 lazy_static! {
-    static ref REPEAT_DELAYS: (u64, u64) = {
+    static ref REPEAT_DELAYS: RepeatDelays = {
         let mut delay = 0u32;
         unsafe {
             SystemParametersInfoW(
@@ -257,114 +644,277 @@ lazy_static! {
         }
         // approximate conversion rates
         // extrapolated from MSDN
-        (
-            250 + 250 * (delay as u64),
-            400 - 12 * (speed as u64)
-        )
+        RepeatDelays {
+            delay_ms: 250 + 250 * (delay as u64),
+            rate_ms: 400 - 12 * (speed as u64),
+        }
     };
 }
 
-impl KeyRepeatThread {
-    fn new(keys: VKSeq) -> Self {
-        let (tx, rx) = mpsc::channel();
-        thread::spawn(move || {
-            if rx
-                .recv_timeout(Duration::from_millis(REPEAT_DELAYS.0))
-                .is_ok()
-            {
-                return;
-            }
-            loop {
-                keys.up().unwrap();
-                keys.down().unwrap();
-                if rx
-                    .recv_timeout(Duration::from_millis(REPEAT_DELAYS.1))
-                    .is_ok()
-                {
-                    return;
-                }
-            }
-        });
-        KeyRepeatThread { channel: tx }
-    }
-}
-
-impl Drop for KeyRepeatThread {
-    fn drop(&mut self) {
-        self.channel.send(()).unwrap();
-    }
-}
-
 pub struct KeyInputManager {
-    pool: RefCell<HashMap<u8, KeyRepeatThread>>,
+    output: Arc<dyn Output>,
+    scheduler: Scheduler,
+    default_repeat: Option<Repeat>,
+    default_injection: Injection,
 }
 
 impl KeyInputManager {
-    pub fn new() -> Self {
+    pub fn new(default_repeat: Option<Repeat>, default_injection: Injection) -> Self {
         KeyInputManager {
-            pool: RefCell::new(HashMap::new()),
+            output: Arc::new(SendInputOutput),
+            scheduler: Scheduler::new(),
+            default_repeat,
+            default_injection,
         }
     }
 
-    pub fn down(&self, id: u8, keys: &VKSeq) -> Result<(), WinError> {
-        keys.down()?;
-        let mut repeat_thread = self.pool.borrow_mut();
-        let thread = KeyRepeatThread::new(keys.clone());
-        repeat_thread.insert(id, thread);
+    /// Picks the delays for a mapping, `None` if it shouldn't repeat.
+    ///
+    /// `repeat = true` uses the delays from the `[general]` section if there are some, and the
+    /// system keyboard settings otherwise.
+    fn repeat_delays(&self, repeat: Option<Repeat>) -> Option<RepeatDelays> {
+        match repeat.or(self.default_repeat) {
+            Some(Repeat::Enabled(false)) => None,
+            Some(Repeat::Custom(delays)) => Some(delays),
+            None | Some(Repeat::Enabled(true)) => match self.default_repeat {
+                Some(Repeat::Custom(delays)) => Some(delays),
+                _ => Some(*REPEAT_DELAYS),
+            },
+        }
+    }
+
+    pub fn down(
+        &self,
+        id: MappingKey,
+        keys: &KeySequence,
+        repeat: Option<Repeat>,
+        injection: Option<Injection>,
+    ) -> Result<(), WinError> {
+        let injection = injection.unwrap_or(self.default_injection);
+        let (held, tapped) = keys.0.split_last().expect("key sequences aren't empty");
+        let mut events = Vec::new();
+        for combo in tapped {
+            events.extend(combo.events(KeyAction::Down, injection));
+            events.extend(combo.events(KeyAction::Up, injection));
+        }
+        events.extend(held.events(KeyAction::Down, injection));
+        self.output.send(&HELD.track(&events))?;
+        let repeated = held.repeated().events(KeyAction::Down, injection);
+        let output = self.output.clone();
+        self.start_repeat(id, repeat, move || output.send(&HELD.repeated(&repeated)));
         Ok(())
     }
 
-    pub fn up(&self, id: u8, keys: &VKSeq) -> Result<(), WinError> {
-        let mut repeat_thread = self.pool.borrow_mut();
-        repeat_thread.remove(&id);
-        keys.up()
+    pub fn up(
+        &self,
+        id: MappingKey,
+        keys: &KeySequence,
+        injection: Option<Injection>,
+    ) -> Result<(), WinError> {
+        self.stop_repeat(id);
+        let injection = injection.unwrap_or(self.default_injection);
+        let held = keys.0.last().expect("key sequences aren't empty");
+        self.output
+            .send(&HELD.track(&held.events(KeyAction::Up, injection)))
     }
-}
 
-impl VK {
-    fn send_event(&self, action: KeyAction) -> Result<(), WinError> {
-        unsafe {
-            let input = [INPUT {
-                r#type: INPUT_KEYBOARD,
-                Anonymous: INPUT_0 {
-                    ki: KEYBDINPUT {
-                        wVk: self.into(),
-                        wScan: 0,
-                        dwFlags: action.into(),
-                        time: 0,
-                        dwExtraInfo: 0,
-                    },
-                },
-            }];
-            if SendInput(&input, std::mem::size_of::<INPUT>() as i32) == 0 {
-                Err(WinError::from_win32())
-            } else {
-                Ok(())
+    pub fn mouse_down(
+        &self,
+        id: MappingKey,
+        action: MouseAction,
+        repeat: Option<Repeat>,
+    ) -> Result<(), WinError> {
+        if let Some(event) = action.event(KeyAction::Down) {
+            self.output.send(&HELD.track(&[event]))?;
+            if !matches!(action, MouseAction::Button(_)) {
+                let output = self.output.clone();
+                self.start_repeat(id, repeat, move || output.send(&HELD.repeated(&[event])));
             }
         }
+        Ok(())
+    }
+
+    pub fn mouse_up(&self, id: MappingKey, action: MouseAction) -> Result<(), WinError> {
+        self.stop_repeat(id);
+        match action.event(KeyAction::Up) {
+            Some(event) => self.output.send(&HELD.track(&[event])),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs `action` repeatedly until `stop_repeat` is called, unless repeat is disabled.
+    fn start_repeat<F>(&self, id: MappingKey, repeat: Option<Repeat>, action: F)
+    where
+        F: FnMut() -> Result<(), WinError> + Send + 'static,
+    {
+        if let Some(delays) = self.repeat_delays(repeat) {
+            self.scheduler.start(
+                id,
+                Duration::from_millis(delays.delay_ms),
+                Duration::from_millis(delays.rate_ms),
+                Box::new(action),
+            );
+        }
     }
 
-    pub fn down(&self) -> Result<(), WinError> {
-        self.send_event(KeyAction::Down)
+    fn stop_repeat(&self, id: MappingKey) {
+        self.scheduler.stop(id);
     }
+}
 
-    pub fn up(&self) -> Result<(), WinError> {
-        self.send_event(KeyAction::Up)
+impl VK {
+    pub fn code(&self) -> u8 {
+        self.0
+    }
+
+    pub fn is_modifier(&self) -> bool {
+        matches!(
+            self.0,
+            0x10..=0x12 // SHIFT, CONTROL, MENU
+            | 0x5B | 0x5C // LWIN, RWIN
+            | 0xA0..=0xA5 // LSHIFT, RSHIFT, LCONTROL, RCONTROL, LMENU, RMENU
+        )
+    }
+
+    /// The scan code and whether it's an extended key, if there is one for this VK.
+    pub fn scan_code(&self) -> Option<(u16, bool)> {
+        SCAN_CODES
+            .get(&self.0)
+            .map(|code| (code & 0xFF, code & 0xFF00 == 0xE000))
+    }
+
+    pub fn input(&self, action: KeyAction, injection: Injection) -> INPUT {
+        let (vk, scan, flags) = match (injection, self.scan_code()) {
+            (Injection::Scancode, Some((scan, extended))) => {
+                let mut flags = KEYEVENTF_SCANCODE | KEYBD_EVENT_FLAGS::from(action);
+                if extended {
+                    flags |= KEYEVENTF_EXTENDEDKEY;
+                }
+                (VIRTUAL_KEY(0), scan, flags)
+            }
+            _ => (self.into(), 0, action.into()),
+        };
+        INPUT {
+            r#type: INPUT_KEYBOARD,
+            Anonymous: INPUT_0 {
+                ki: KEYBDINPUT {
+                    wVk: vk,
+                    wScan: scan,
+                    dwFlags: flags,
+                    time: 0,
+                    dwExtraInfo: 0,
+                },
+            },
+        }
     }
 }
 
 impl VKSeq {
-    fn down(&self) -> Result<(), WinError> {
-        for vk in self.0.iter() {
-            vk.down()?;
+    /// The keys a real keyboard would repeat while the combination is held: the non-modifier
+    /// keys, or the last key if they're all modifiers. Repeating only sends key-down events,
+    /// so the modifiers stay held.
+    fn repeated(&self) -> VKSeq {
+        let keys: Vec<VK> = self
+            .0
+            .iter()
+            .filter(|vk| !vk.is_modifier())
+            .copied()
+            .collect();
+        if keys.is_empty() {
+            VKSeq(self.0.last().copied().into_iter().collect())
+        } else {
+            VKSeq(keys)
         }
-        Ok(())
     }
 
-    fn up(&self) -> Result<(), WinError> {
-        for vk in self.0.iter().rev() {
-            vk.up()?;
+    /// Presses the keys in order, or releases them in reverse order.
+    fn events(&self, action: KeyAction, injection: Injection) -> Vec<InputEvent> {
+        let event = |vk: &VK| InputEvent::Key(*vk, action, injection);
+        match action {
+            KeyAction::Down => self.0.iter().map(event).collect(),
+            KeyAction::Up => self.0.iter().rev().map(event).collect(),
         }
-        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KeySequence, KeySequenceError, VKError, VK};
+
+    fn vk(s: &str) -> Result<VK, VKError> {
+        s.parse()
+    }
+
+    fn sequence_error(s: &str) -> KeySequenceError {
+        match s.parse::<KeySequence>() {
+            Ok(_) => panic!("{} parsed", s),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn parses_names_aliases_and_codes() {
+        assert_eq!(vk("CONTROL").unwrap(), VK(0x11));
+        assert_eq!(vk("vk_lcontrol").unwrap(), VK(0xA2));
+        assert_eq!(vk("VK_LControl").unwrap(), VK(0xA2));
+        assert_eq!(vk("Ctrl").unwrap(), VK(0x11));
+        assert_eq!(vk(" esc ").unwrap(), VK(0x1B));
+        assert_eq!(vk("0xA2").unwrap(), VK(0xA2));
+        assert_eq!(vk("0XA2").unwrap(), VK(0xA2));
+        assert_eq!(VK::try_from(0xA2).unwrap(), VK(0xA2));
+    }
+
+    #[test]
+    fn rejects_codes_out_of_range() {
+        assert!(matches!(vk("0x00"), Err(VKError::OutOfRange(0))));
+        assert!(matches!(vk("0x1FF"), Err(VKError::OutOfRange(0x1FF))));
+        assert!(matches!(VK::try_from(-1), Err(VKError::OutOfRange(-1))));
+        assert!(matches!(VK::try_from(256), Err(VKError::OutOfRange(256))));
+        assert!(matches!(vk("0xZZ"), Err(VKError::Unknown(name, None)) if name == "0xZZ"));
+    }
+
+    #[test]
+    fn suggests_close_names() {
+        assert!(matches!(
+            vk("Shft"),
+            Err(VKError::Unknown(name, Some("SHIFT"))) if name == "Shft"
+        ));
+        assert!(matches!(
+            vk("vk_lcontrl"),
+            Err(VKError::Unknown(_, Some("LCONTROL")))
+        ));
+        assert!(matches!(vk("Nonsense"), Err(VKError::Unknown(_, None))));
+    }
+
+    #[test]
+    fn parses_sequences() {
+        let sequence: KeySequence = "  Ctrl+K Ctrl+C".parse().unwrap();
+        assert_eq!(sequence.0.len(), 2);
+        assert_eq!(
+            sequence.keys().copied().collect::<Vec<_>>(),
+            [VK(0x11), VK(0x4B), VK(0x11), VK(0x43)]
+        );
+    }
+
+    #[test]
+    fn points_to_the_failing_token() {
+        let err = sequence_error("Ctrl+");
+        assert_eq!(err.column, 6);
+        assert!(matches!(err.error, VKError::Missing));
+        let err = sequence_error("Ctrl++K");
+        assert_eq!(err.column, 6);
+        assert!(matches!(err.error, VKError::Missing));
+        let err = sequence_error("Ctrl+Shft");
+        assert_eq!(err.column, 6);
+        assert_eq!(
+            err.to_string(),
+            "Unknown VK: Shft (did you mean SHIFT?), at column 6 of \"Ctrl+Shft\""
+        );
+        assert_eq!(sequence_error("  Ctrl+K Ctrl+Bad").column, 15);
+        // in characters, not bytes
+        assert_eq!(sequence_error("\u{a0}Ctrl+Bad").column, 7);
+        let err = sequence_error("   ");
+        assert_eq!(err.column, 1);
+        assert!(matches!(err.error, VKError::Missing));
     }
 }